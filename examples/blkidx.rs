@@ -20,13 +20,14 @@ fn main() {
             std::thread::sleep(Duration::from_secs(5));
             // compute balance
             let start = Instant::now();
-            let sum: f64 = indexer
+            let sum = indexer
                 .query_coins()
                 .unspent()
                 .denom(Denom::Mel)
-                .iter()
-                .map(|d| d.coin_data.value.0 as f64 / 1_000_000.0)
-                .sum();
+                .sum_value()
+                .unwrap()
+                .0 as f64
+                / 1_000_000.0;
             eprintln!("{} MEL in circulation {:?}", sum, start.elapsed());
         }
     });
@@ -3,7 +3,7 @@ use std::collections::BTreeMap;
 use melstructs::CoinValue;
 use parking_lot::Mutex;
 
-use crate::CoinQuery;
+use crate::{error::retry_transient, CoinQuery};
 
 /// Tracks the balance (sum of values) of all coins fulfilling some condition specified by the given CoinQuery, that are alive at a given height. Intelligently caches and plans around previous queries to avoid scanning all coins.
 pub struct BalanceTracker {
@@ -11,23 +11,16 @@ pub struct BalanceTracker {
     cache: Mutex<BTreeMap<u64, CoinValue>>,
 }
 
-unsafe impl Sync for BalanceTracker {}
-
 impl BalanceTracker {
     /// Returns how much the balance changed between the start (not inclusive) and the end (inclusive)
-    fn balance_diff(&self, start: u64, end: u64) -> i128 {
+    fn balance_diff(&self, start: u64, end: u64) -> Option<i128> {
         // Coins created between the two, minus coins spent
-        self.query
-            .clone()
-            .create_height_range(start + 1..=end)
-            .iter()
-            .fold(0i128, |a, b| a + b.coin_data.value.0 as i128)
-            - self
-                .query
-                .clone()
-                .spend_height_range(start + 1..=end)
-                .iter()
-                .fold(0i128, |a, b| a + b.coin_data.value.0 as i128)
+        let created = self.query.clone().create_height_range(start + 1..=end);
+        let spent = self.query.clone().spend_height_range(start + 1..=end);
+        Some(
+            retry_transient(|| created.sum_value()).ok()?.0 as i128
+                - retry_transient(|| spent.sum_value()).ok()?.0 as i128,
+        )
     }
 
     /// Creates a new balance tracker
@@ -38,7 +31,7 @@ impl BalanceTracker {
         }
     }
 
-    /// Queries the balance at a given height.
+    /// Queries the balance at a given height. Returns None if the database can't be read, or the query reaches below the indexed range.
     pub fn balance_at(&self, height: u64) -> Option<CoinValue> {
         if let Some(val) = self.cache.lock().get(&height).copied() {
             log::debug!("{} direct hit", height);
@@ -46,22 +39,14 @@ impl BalanceTracker {
         }
         // If the cache is empty, just go from scratch
         if self.cache.lock().is_empty() {
-            let result = self
+            let unspent = self.query.clone().create_height_range(..=height).unspent();
+            let spent_later = self
                 .query
                 .clone()
                 .create_height_range(..=height)
-                .unspent()
-                .iter()
-                .map(|s| s.coin_data.value)
-                .fold(CoinValue(0), |a, b| a + b)
-                + self
-                    .query
-                    .clone()
-                    .create_height_range(..=height)
-                    .spend_height_range(height + 1..)
-                    .iter()
-                    .map(|s| s.coin_data.value)
-                    .fold(CoinValue(0), |a, b| a + b);
+                .spend_height_range(height + 1..);
+            let result = retry_transient(|| unspent.sum_value()).ok()?
+                + retry_transient(|| spent_later.sum_value()).ok()?;
             self.cache.lock().insert(height, result);
             log::debug!("{} total miss", height);
             return Some(result);
@@ -86,13 +71,13 @@ impl BalanceTracker {
         let balance = if height.abs_diff(next_height) < height.abs_diff(prev_height) {
             log::debug!("{} from next {}", height, next_height);
             // compute from *next* closest height
-            let diff = self.balance_diff(height, next_height);
-            next_balance - (diff as u128).into()
+            let diff = self.balance_diff(height, next_height)?;
+            CoinValue((next_balance.0 as i128 - diff) as u128)
         } else {
             log::debug!("{} from prev {}", height, prev_height);
             // compute from *previous * closest height
-            let diff = self.balance_diff(prev_height, height);
-            prev_balance + (diff as u128).into()
+            let diff = self.balance_diff(prev_height, height)?;
+            CoinValue((prev_balance.0 as i128 + diff) as u128)
         };
         if balance != prev_balance && balance != next_balance {
             self.cache.lock().insert(height, balance);
@@ -1,11 +1,20 @@
-use std::{ops::RangeBounds, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    ops::{Bound, Not, RangeBounds},
+    str::FromStr,
+};
 
-use genawaiter::sync::Gen;
-use itertools::Itertools;
 use melstructs::{Address, BlockHeight, CoinData, CoinValue, Denom, TxHash};
-use rusqlite::ToSql;
+use rusqlite::types::Value;
+use smol::stream::{self, Stream, StreamExt};
 
-use crate::{pool::Pool, repeat_fallible, BalanceTracker};
+use crate::{
+    error::retry_transient,
+    filter::{query_rows, Filters},
+    pool::Pool,
+    range, BalanceTracker, Error,
+};
 
 /// Info about a coin.
 #[derive(Clone, Debug, PartialEq, PartialOrd, Ord, Eq)]
@@ -25,29 +34,137 @@ pub struct CoinSpendInfo {
     pub spend_height: BlockHeight,
 }
 
+/// A field that coins can be ordered by. Ties are broken by creation height, txhash and index, so that the order is total.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoinOrder {
+    CreateHeight,
+    Value,
+}
+
+impl CoinOrder {
+    fn columns(&self) -> &'static [&'static str] {
+        match self {
+            CoinOrder::CreateHeight => &["create_height", "create_txhash", "create_index"],
+            CoinOrder::Value => &["value", "create_height", "create_txhash", "create_index"],
+        }
+    }
+}
+
+/// A sort direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+/// An opaque position in an ordered list of coins, used to fetch the page after it. Made from the last coin of a page, and convertible to and from a string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoinCursor {
+    create_height: u64,
+    create_txhash: TxHash,
+    create_index: u8,
+    value: u128,
+}
+
+impl CoinCursor {
+    fn key(&self, order: CoinOrder) -> Vec<Value> {
+        let mut key: Vec<Value> = vec![
+            (self.create_height as i64).into(),
+            self.create_txhash.to_string().into(),
+            self.create_index.into(),
+        ];
+        if order == CoinOrder::Value {
+            key.insert(0, self.value.to_be_bytes().to_vec().into());
+        }
+        key
+    }
+}
+
+impl From<&CoinInfo> for CoinCursor {
+    fn from(coin: &CoinInfo) -> Self {
+        Self {
+            create_height: coin.create_height.0,
+            create_txhash: coin.create_txhash,
+            create_index: coin.create_index,
+            value: coin.coin_data.value.0,
+        }
+    }
+}
+
+impl Display for CoinCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut bytes = Vec::with_capacity(57);
+        bytes.extend_from_slice(&self.create_height.to_be_bytes());
+        bytes.extend_from_slice(&self.create_txhash.0 .0);
+        bytes.push(self.create_index);
+        bytes.extend_from_slice(&self.value.to_be_bytes());
+        f.write_str(&hex::encode(bytes))
+    }
+}
+
+impl FromStr for CoinCursor {
+    type Err = InvalidCursor;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| InvalidCursor)?;
+        if bytes.len() != 57 {
+            return Err(InvalidCursor);
+        }
+        Ok(Self {
+            create_height: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            create_txhash: TxHash(tmelcrypt::HashVal(bytes[8..40].try_into().unwrap())),
+            create_index: bytes[40],
+            value: u128::from_be_bytes(bytes[41..57].try_into().unwrap()),
+        })
+    }
+}
+
+/// Returned when parsing a malformed [CoinCursor].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidCursor;
+
+impl Display for InvalidCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid cursor")
+    }
+}
+
+impl std::error::Error for InvalidCursor {}
+
+/// How many coins [`CoinQuery::try_stream`] fetches at a time.
+const STREAM_BATCH: u64 = 1000;
+
 /// A half-built query on the coins table
 #[derive(Clone)]
 pub struct CoinQuery {
     pub(crate) pool: Pool,
 
-    filters: Vec<String>,
-    params: Vec<Arc<dyn ToSql>>,
+    filters: Filters,
+    order: Option<(CoinOrder, Direction)>,
+    after: Option<CoinCursor>,
+    limit: Option<u64>,
+    /// The lowest creation height the constraints allow, used to tell when the query reaches below the indexed range. None if the query is pinned to a transaction, which is either indexed in full or not at all.
+    create_floor: Option<u64>,
+    /// Whether the query asks what was held at a height, so that even listing its coins is wrong without the history before it
+    snapshot: bool,
 }
 
-// TODO get rid of this
-unsafe impl Send for CoinQuery {}
-
 impl CoinQuery {
     pub(crate) fn new(pool: Pool) -> Self {
         Self {
             pool,
-            filters: vec![],
-            params: vec![],
+            filters: Filters::default(),
+            order: None,
+            after: None,
+            limit: None,
+            create_floor: Some(0),
+            snapshot: false,
         }
     }
 
     /// Adds a constraint on the creation txhash.
-    pub fn create_txhash(self, txhash: TxHash) -> Self {
+    pub fn create_txhash(mut self, txhash: TxHash) -> Self {
+        self.create_floor = None;
         self.add_eq_filter("create_txhash", txhash.to_string())
     }
 
@@ -57,26 +174,35 @@ impl CoinQuery {
     }
 
     /// Adds a constraint on the creation height.
-    pub fn create_height_range(self, range: impl RangeBounds<u64>) -> Self {
-        self.add_range_filter("create_height", range, |f| *f)
+    pub fn create_height_range(mut self, range: impl RangeBounds<u64>) -> Self {
+        let floor = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => start.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        self.create_floor = self.create_floor.map(|f| f.max(floor));
+        self.add_range_filter("create_height", range, |f| *f as i64)
     }
 
     /// Adds a constraint that filters only for unspent coins.
     pub fn unspent(mut self) -> Self {
-        self.filters.push("spend_txhash is null".into());
+        self.filters.add_clause("spend_txhash is null", []);
         self
     }
 
-    /// Adds a constraint that filters only for coins unspent by a certain height.
+    /// Adds a constraint that filters only for coins unspent by a certain height. Unless indexing started from genesis, even iterating fails with [Error::BelowIndexedRange], since coins held since before the start are missing.
     pub fn unspent_by(mut self, height: BlockHeight) -> Self {
-        self.filters
-            .push("(spend_txhash is null or spend_height > ?)".into());
-        self.params.push(Arc::new(height.0));
+        self.snapshot = true;
+        self.filters.add_clause(
+            "(spend_txhash is null or spend_height > ?)",
+            [(height.0 as i64).into()],
+        );
         self.create_height_range(..=height.0)
     }
 
     /// Adds a constraint on the spending txhash.
-    pub fn spend_txhash(self, txhash: TxHash) -> Self {
+    pub fn spend_txhash(mut self, txhash: TxHash) -> Self {
+        self.create_floor = None;
         self.add_eq_filter("spend_txhash", txhash.to_string())
     }
 
@@ -87,12 +213,12 @@ impl CoinQuery {
 
     /// Adds a constraint on the creation height.
     pub fn spend_height_range(self, range: impl RangeBounds<u64>) -> Self {
-        self.add_range_filter("spend_height", range, |f| *f)
+        self.add_range_filter("spend_height", range, |f| *f as i64)
     }
 
     /// Adds a constraint on the value.
     pub fn value_range(self, range: impl RangeBounds<CoinValue>) -> Self {
-        self.add_range_filter("value", range, |f| f.0.to_be_bytes())
+        self.add_range_filter("value", range, |f| f.0.to_be_bytes().to_vec())
     }
 
     /// Adds a constraint on the denom.
@@ -105,47 +231,123 @@ impl CoinQuery {
         self.add_eq_filter("covhash", covhash.to_string())
     }
 
+    /// Adds a constraint that the covhash is one of the given ones.
+    pub fn covhash_in(mut self, covhashes: &[Address]) -> Self {
+        self.filters
+            .add_in("covhash", covhashes.iter().map(|c| c.to_string()));
+        self
+    }
+
+    /// Adds a constraint that the denom is one of the given ones.
+    pub fn denom_in(mut self, denoms: &[Denom]) -> Self {
+        self.filters
+            .add_in("denom", denoms.iter().map(|d| d.to_bytes().to_vec()));
+        self
+    }
+
+    /// Matches coins matching either this query or the other one. Only the other query's constraints are used; ordering, cursors and limits come from this one.
+    pub fn or(mut self, other: CoinQuery) -> Self {
+        self.filters = self.filters.or(other.filters);
+        self.create_floor = match (self.create_floor, other.create_floor) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.snapshot |= other.snapshot;
+        self
+    }
+
     /// Adds a constraint on the additional data.
     pub fn additional_data(self, additional_data: &[u8]) -> Self {
         self.add_eq_filter("additional_data", additional_data.to_vec())
     }
 
-    fn add_eq_filter<T: ToSql + 'static>(mut self, field: &str, val: T) -> Self {
-        self.filters.push(format!("{} == ?", field));
-        self.params.push(Arc::new(val));
+    fn add_eq_filter(mut self, field: &str, val: impl Into<Value>) -> Self {
+        self.filters.add_eq(field, val);
         self
     }
 
-    fn add_range_filter<T, U: ToSql + 'static>(
+    fn add_range_filter<T, U: Into<Value>>(
         mut self,
         field: &str,
         range: impl RangeBounds<T>,
         f: impl Fn(&T) -> U,
     ) -> Self {
-        match range.start_bound() {
-            std::ops::Bound::Included(v) => {
-                self.filters.push(format!("{} >= ?", field));
-                self.params.push(Arc::new(f(v)));
-            }
-            std::ops::Bound::Excluded(v) => {
-                self.filters.push(format!("{} > ?", field));
-                self.params.push(Arc::new(f(v)));
-            }
-            std::ops::Bound::Unbounded => {}
+        self.filters.add_range(field, range, f);
+        self
+    }
+
+    /// Orders the results. Without this, results come in no particular order.
+    pub fn order_by(mut self, order: CoinOrder, direction: Direction) -> Self {
+        self.order = Some((order, direction));
+        self
+    }
+
+    /// Only returns coins that come after the given cursor, in the query's order. Defaults to ordering by ascending creation height if no order was given.
+    pub fn after(mut self, cursor: CoinCursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// Returns at most this many coins.
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// The full select statement for iterating, with its parameters.
+    fn select(&self) -> (String, Vec<Value>) {
+        let mut filters = self.filters.clone();
+        let order = self.order.or_else(|| {
+            self.after
+                .map(|_| (CoinOrder::CreateHeight, Direction::Asc))
+        });
+        if let (Some(cursor), Some((order, direction))) = (self.after, order) {
+            let columns = order.columns();
+            filters.add_clause(
+                format!(
+                    "({}) {} ({})",
+                    columns.join(", "),
+                    if direction == Direction::Asc {
+                        ">"
+                    } else {
+                        "<"
+                    },
+                    vec!["?"; columns.len()].join(", ")
+                ),
+                cursor.key(order),
+            );
+        }
+        let mut query = format!("select * from coins where {}", filters.sql());
+        let mut params = filters.owned_params();
+        if let Some((order, direction)) = order {
+            let direction = if direction == Direction::Asc {
+                "asc"
+            } else {
+                "desc"
+            };
+            query.push_str(" order by ");
+            query.push_str(
+                &order
+                    .columns()
+                    .iter()
+                    .map(|column| format!("{} {}", column, direction))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        }
+        if let Some(limit) = self.limit {
+            query.push_str(" limit ?");
+            params.push((limit as i64).into());
         }
+        (query, params)
+    }
 
-        match range.end_bound() {
-            std::ops::Bound::Included(v) => {
-                self.filters.push(format!("{} <= ?", field));
-                self.params.push(Arc::new(f(v)));
-            }
-            std::ops::Bound::Excluded(v) => {
-                self.filters.push(format!("{} < ?", field));
-                self.params.push(Arc::new(f(v)));
-            }
-            std::ops::Bound::Unbounded => {}
+    /// Fails if the query reaches below the indexed range, since the coins created there are missing.
+    fn check_range(&self) -> Result<(), Error> {
+        match self.create_floor {
+            Some(floor) => range::check_floor(&*self.pool.get_conn()?, floor),
+            None => Ok(()),
         }
-        self
     }
 
     /// Create a cached balance tracker from this query.
@@ -153,58 +355,375 @@ impl CoinQuery {
         BalanceTracker::new(self)
     }
 
-    /// Iterate through all the coins matching this filter
+    /// Iterate through all the indexed coins matching this filter. Panics if the database can't be read, but never because of the indexed range. The iterator holds a pooled connection until it's dropped.
     pub fn iter(&self) -> impl Iterator<Item = CoinInfo> + '_ {
-        let gen = Gen::new(|co| async move {
-            let query = format!(
-                "select * from coins where {}",
-                self.filters.iter().join(" and ")
-            );
-            log::debug!("iter query: {:?}", query);
-            let conn = self.pool.get_conn();
-            let mut stmt = repeat_fallible(|| conn.prepare_cached(&query));
-            let params: Vec<&dyn ToSql> = self.params.iter().map(|f| f.as_ref()).collect_vec();
-
-            let i = stmt
-                .query_map(&params[..], |row| {
-                    let create_txhash: String = row.get(0)?;
-                    let create_txhash = TxHash(create_txhash.parse().unwrap());
-                    let create_index: u8 = row.get(1)?;
-                    let create_height: BlockHeight = BlockHeight(row.get(2)?);
-                    let spend_txhash: Option<String> = row.get(3)?;
-                    let spend_txhash: Option<TxHash> =
-                        spend_txhash.map(|x| TxHash(x.parse().unwrap()));
-                    let spend_index: Option<usize> = row.get(4)?;
-                    let spend_height: Option<u64> = row.get(5)?;
-                    let spend_height: Option<BlockHeight> = spend_height.map(|h| h.into());
-                    let value: CoinValue = u128::from_be_bytes(row.get(6)?).into();
-                    let denom: Vec<u8> = row.get(7)?;
-                    let denom: Denom = Denom::from_bytes(&denom).unwrap();
-                    let covhash: String = row.get(8)?;
-                    let covhash: Address = covhash.parse().unwrap();
-                    let additional_data: Vec<u8> = row.get(9)?;
-                    Ok(CoinInfo {
-                        create_txhash,
-                        create_index,
-                        create_height,
-                        coin_data: CoinData {
-                            covhash,
-                            value,
-                            denom,
-                            additional_data: additional_data.into(),
-                        },
-                        spend_info: spend_txhash.map(|spend_txhash| CoinSpendInfo {
-                            spend_txhash,
-                            spend_index: spend_index.unwrap(),
-                            spend_height: spend_height.unwrap(),
-                        }),
-                    })
-                })
-                .unwrap();
-            for elem in i {
-                co.yield_(elem.unwrap()).await;
+        retry_transient(|| self.rows())
+            .unwrap()
+            .map(|coin| coin.unwrap())
+    }
+
+    /// Iterate through all the coins matching this filter, returning errors instead of panicking. Fails with [Error::BelowIndexedRange] if the query uses [CoinQuery::unspent_by] and reaches below the indexed range. The iterator holds a pooled connection until it's dropped.
+    pub fn try_iter(&self) -> Result<impl Iterator<Item = Result<CoinInfo, Error>> + '_, Error> {
+        if self.snapshot {
+            self.check_range()?;
+        }
+        self.rows()
+    }
+
+    fn rows(&self) -> Result<impl Iterator<Item = Result<CoinInfo, Error>> + '_, Error> {
+        let (query, params) = self.select();
+        query_rows(&self.pool, query, params, coin_from_row)
+    }
+
+    /// Streams all the indexed coins matching this filter without blocking the executor. Panics if the database can't be read, but never because of the indexed range.
+    pub fn stream(&self) -> impl Stream<Item = CoinInfo> + Send + 'static {
+        self.paged(false).map(|coin| coin.unwrap())
+    }
+
+    /// Streams all the coins matching this filter, returning errors instead of panicking, and checking the indexed range like [CoinQuery::try_iter]. Coins are fetched on the blocking thread pool a batch at a time, paging with cursors, so no connection is held between batches. Defaults to ordering by ascending creation height if no order was given. The stream ends after the first error.
+    pub fn try_stream(&self) -> impl Stream<Item = Result<CoinInfo, Error>> + Send + 'static {
+        self.paged(self.snapshot)
+    }
+
+    /// Streams coins a batch at a time, checking the indexed range before the first batch if asked to.
+    fn paged(&self, check: bool) -> impl Stream<Item = Result<CoinInfo, Error>> + Send + 'static {
+        let (order, direction) = self
+            .order
+            .unwrap_or((CoinOrder::CreateHeight, Direction::Asc));
+        let start = Some((self.clone().order_by(order, direction), self.limit, check));
+        stream::unfold(start, |state| async move {
+            let (query, remaining, check) = state?;
+            let batch_size = remaining.map_or(STREAM_BATCH, |r| r.min(STREAM_BATCH));
+            if batch_size == 0 {
+                return None;
             }
-        });
-        gen.into_iter()
+            let batch = query.clone().limit(batch_size);
+            let coins = smol::unblock(move || {
+                if check {
+                    retry_transient(|| batch.check_range())?;
+                }
+                retry_transient(|| batch.rows()?.collect::<Result<Vec<_>, _>>())
+            })
+            .await;
+            match coins {
+                Ok(coins) => {
+                    let next = match coins.last() {
+                        Some(last) if coins.len() as u64 == batch_size => Some((
+                            query.after(last.into()),
+                            remaining.map(|r| r - batch_size),
+                            false,
+                        )),
+                        _ => None,
+                    };
+                    Some((coins.into_iter().map(Ok).collect::<Vec<_>>(), next))
+                }
+                Err(err) => Some((vec![Err(err)], None)),
+            }
+        })
+        .flat_map(stream::iter)
+    }
+
+    /// Counts the coins matching this filter. Like the other aggregates, this ignores ordering, cursors and limits, and fails with [Error::BelowIndexedRange] if the creation height isn't constrained to the indexed range, unless the query is pinned to a transaction.
+    pub fn count(&self) -> Result<u64, Error> {
+        self.check_range()?;
+        let conn = self.pool.get_conn()?;
+        Ok(conn.query_row(
+            &format!("select count(*) from coins where {}", self.filters.sql()),
+            &self.filters.params()[..],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Sums up the values of the coins matching this filter. This is only meaningful when the filter restricts to a single denomination.
+    pub fn sum_value(&self) -> Result<CoinValue, Error> {
+        self.check_range()?;
+        let conn = self.pool.get_conn()?;
+        Ok(conn.query_row(
+            &format!(
+                "select u128_sum(value) from coins where {}",
+                self.filters.sql()
+            ),
+            &self.filters.params()[..],
+            |row| Ok(u128::from_be_bytes(row.get(0)?).into()),
+        )?)
+    }
+
+    /// Sums up the values of the coins matching this filter, per covhash. This is only meaningful when the filter restricts to a single denomination.
+    pub fn group_by_covhash(&self) -> Result<BTreeMap<Address, CoinValue>, Error> {
+        self.check_range()?;
+        query_rows(
+            &self.pool,
+            format!(
+                "select covhash, u128_sum(value) from coins where {} group by covhash",
+                self.filters.sql()
+            ),
+            self.filters.owned_params(),
+            |row| {
+                let covhash: String = row.get(0)?;
+                let covhash: Address = covhash
+                    .parse()
+                    .map_err(|_| Error::corrupt("covhash", &covhash))?;
+                Ok((covhash, u128::from_be_bytes(row.get(1)?).into()))
+            },
+        )?
+        .collect()
+    }
+
+    /// Sums up the values of the coins matching this filter, per denomination.
+    pub fn group_by_denom(&self) -> Result<BTreeMap<Denom, CoinValue>, Error> {
+        self.check_range()?;
+        query_rows(
+            &self.pool,
+            format!(
+                "select denom, u128_sum(value) from coins where {} group by denom",
+                self.filters.sql()
+            ),
+            self.filters.owned_params(),
+            |row| {
+                let denom: Vec<u8> = row.get(0)?;
+                let denom =
+                    Denom::from_bytes(&denom).ok_or_else(|| Error::corrupt("denom", &denom))?;
+                Ok((denom, u128::from_be_bytes(row.get(1)?).into()))
+            },
+        )?
+        .collect()
+    }
+}
+
+impl Not for CoinQuery {
+    type Output = CoinQuery;
+
+    /// Matches exactly the coins this query's constraints don't match. Ordering, cursors and limits are kept as-is.
+    fn not(mut self) -> Self {
+        self.filters = self.filters.not();
+        self.create_floor = Some(0);
+        self.snapshot = false;
+        self
+    }
+}
+
+pub(crate) fn coin_from_row(row: &rusqlite::Row) -> Result<CoinInfo, Error> {
+    let create_txhash: String = row.get(0)?;
+    let create_txhash = parse_txhash(&create_txhash)?;
+    let create_index: u8 = row.get(1)?;
+    let create_height: BlockHeight = BlockHeight(row.get(2)?);
+    let spend_txhash: Option<String> = row.get(3)?;
+    let spend_txhash: Option<TxHash> = spend_txhash.as_deref().map(parse_txhash).transpose()?;
+    let spend_index: Option<usize> = row.get(4)?;
+    let spend_height: Option<u64> = row.get(5)?;
+    let spend_height: Option<BlockHeight> = spend_height.map(|h| h.into());
+    let value: CoinValue = u128::from_be_bytes(row.get(6)?).into();
+    let denom: Vec<u8> = row.get(7)?;
+    let denom: Denom = Denom::from_bytes(&denom).ok_or_else(|| Error::corrupt("denom", &denom))?;
+    let covhash: String = row.get(8)?;
+    let covhash: Address = covhash
+        .parse()
+        .map_err(|_| Error::corrupt("covhash", &covhash))?;
+    let additional_data: Vec<u8> = row.get(9)?;
+    let spend_info = match (spend_txhash, spend_index, spend_height) {
+        (Some(spend_txhash), Some(spend_index), Some(spend_height)) => Some(CoinSpendInfo {
+            spend_txhash,
+            spend_index,
+            spend_height,
+        }),
+        (None, _, _) => None,
+        _ => return Err(Error::corrupt("spend info for coin", create_txhash)),
+    };
+    Ok(CoinInfo {
+        create_txhash,
+        create_index,
+        create_height,
+        coin_data: CoinData {
+            covhash,
+            value,
+            denom,
+            additional_data: additional_data.into(),
+        },
+        spend_info,
+    })
+}
+
+pub(crate) fn parse_txhash(s: &str) -> Result<TxHash, Error> {
+    s.parse()
+        .map(TxHash)
+        .map_err(|_| Error::corrupt("txhash", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+    use crate::{
+        testutil::{address, txhash, TestDb},
+        IndexerConfig,
+    };
+
+    /// Coins with ties in every ordering column but the last: two transactions at height 1, equal values across heights.
+    fn ties() -> TestDb {
+        let db = TestDb::new(IndexerConfig::default());
+        for (created, value) in [
+            ((1, 0, 1), 5),
+            ((1, 1, 1), 3),
+            ((2, 0, 1), 5),
+            ((2, 1, 1), 9),
+            ((3, 0, 2), 5),
+            ((3, 1, 2), 1),
+            ((4, 0, 3), 9),
+        ] {
+            db.add_coin(created, 1, Denom::Mel, value);
+        }
+        db
+    }
+
+    fn sort_key(coin: &CoinInfo, order: CoinOrder) -> (u128, u64, TxHash, u8) {
+        let value = match order {
+            CoinOrder::CreateHeight => 0,
+            CoinOrder::Value => coin.coin_data.value.0,
+        };
+        (
+            value,
+            coin.create_height.0,
+            coin.create_txhash,
+            coin.create_index,
+        )
+    }
+
+    #[test]
+    fn pages_through_every_coin_once_in_order() {
+        let db = ties();
+        let query = CoinQuery::new(db.pool().clone());
+        for order in [CoinOrder::CreateHeight, CoinOrder::Value] {
+            for direction in [Direction::Asc, Direction::Desc] {
+                let mut expected = query.iter().collect::<Vec<_>>();
+                expected.sort_by_key(|coin| sort_key(coin, order));
+                if direction == Direction::Desc {
+                    expected.reverse();
+                }
+                let ordered = query.clone().order_by(order, direction);
+                assert_eq!(ordered.iter().collect::<Vec<_>>(), expected);
+
+                let mut paged = vec![];
+                let mut page = ordered.clone().limit(2);
+                loop {
+                    let coins = page.iter().collect::<Vec<_>>();
+                    assert!(coins.len() <= 2);
+                    let last = match coins.last() {
+                        Some(last) => last.into(),
+                        None => break,
+                    };
+                    paged.extend(coins);
+                    page = page.after(last);
+                }
+                assert_eq!(paged, expected, "{:?} {:?}", order, direction);
+            }
+        }
+    }
+
+    #[test]
+    fn cursors_default_to_ascending_creation_height() {
+        let db = ties();
+        let query = CoinQuery::new(db.pool().clone());
+        let first = query.clone().limit(1).iter().next().unwrap();
+        let rest = query
+            .clone()
+            .after((&first).into())
+            .iter()
+            .collect::<Vec<_>>();
+        let all = query
+            .order_by(CoinOrder::CreateHeight, Direction::Asc)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(all[0], first);
+        assert_eq!(rest, all[1..]);
+    }
+
+    #[test]
+    fn streams_in_batches() {
+        let db = TestDb::new(IndexerConfig::default());
+        // enough coins for a few batches, three to a height so that batches end on ties
+        db.pool()
+            .get_conn()
+            .unwrap()
+            .execute(
+                r"with recursive n(i) as (select 0 union all select i + 1 from n where i < 2499)
+                insert into coins select printf('%064x', i), 0, i / 3, NULL, NULL, NULL, zeroblob(16), $1, $2, x'' from n",
+                params![Denom::Mel.to_bytes().to_vec(), address(1).to_string()],
+            )
+            .unwrap();
+        let query = CoinQuery::new(db.pool().clone());
+        let expected = query
+            .clone()
+            .order_by(CoinOrder::CreateHeight, Direction::Desc)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(expected.len(), 2500);
+
+        let streamed = smol::block_on(
+            query
+                .clone()
+                .order_by(CoinOrder::CreateHeight, Direction::Desc)
+                .stream()
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(streamed, expected);
+        let limited = smol::block_on(
+            query
+                .order_by(CoinOrder::CreateHeight, Direction::Desc)
+                .limit(1500)
+                .stream()
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(limited, expected[..1500]);
+    }
+
+    #[test]
+    fn negates_spends_to_include_unspent_coins() {
+        let db = TestDb::new(IndexerConfig::default());
+        db.add_coin((1, 0, 1), 1, Denom::Mel, 1);
+        db.add_coin((1, 1, 1), 1, Denom::Sym, 2);
+        db.add_coin((1, 2, 1), 2, Denom::Mel, 3);
+        db.spend_coin((1, 0), (2, 0, 2));
+        db.spend_coin((1, 1), (3, 0, 2));
+        let query = CoinQuery::new(db.pool().clone());
+        let created = |coins: &CoinQuery| {
+            let mut indexes = coins.iter().map(|c| c.create_index).collect::<Vec<_>>();
+            indexes.sort_unstable();
+            indexes
+        };
+
+        assert_eq!(created(&query.clone().spend_txhash(txhash(2))), [0]);
+        // the unspent coin has no spender, which is still not txhash(2)
+        assert_eq!(created(&!query.clone().spend_txhash(txhash(2))), [1, 2]);
+        assert_eq!(created(&!query.clone().unspent()), [0, 1]);
+        assert_eq!(created(&!!query.clone().unspent()), [2]);
+
+        assert_eq!(created(&query.clone().covhash_in(&[])), [0u8; 0]);
+        assert_eq!(created(&!query.clone().covhash_in(&[])), [0, 1, 2]);
+        let either = query
+            .clone()
+            .covhash(address(2))
+            .or(query.clone().denom(Denom::Sym));
+        assert_eq!(created(&either), [1, 2]);
+        assert_eq!(either.count().unwrap(), 2);
+        assert_eq!(created(&!either), [0]);
+    }
+
+    #[test]
+    fn cursor_roundtrips_through_string() {
+        let cursor = CoinCursor {
+            create_height: 123_456,
+            create_txhash: TxHash(tmelcrypt::HashVal([7; 32])),
+            create_index: 3,
+            value: u128::MAX - 1,
+        };
+        assert_eq!(cursor.to_string().parse::<CoinCursor>(), Ok(cursor));
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        assert_eq!("".parse::<CoinCursor>(), Err(InvalidCursor));
+        assert_eq!("zz".parse::<CoinCursor>(), Err(InvalidCursor));
+        assert_eq!("00".repeat(56).parse::<CoinCursor>(), Err(InvalidCursor));
     }
 }
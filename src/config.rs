@@ -0,0 +1,207 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use melstructs::BlockHeight;
+
+/// Tunes how an [crate::Indexer] syncs and stores its database. The defaults suit most uses; change them with the builder methods.
+#[derive(Clone, Debug)]
+pub struct IndexerConfig {
+    pub(crate) fetch_window: usize,
+    pub(crate) batch_size: usize,
+    pub(crate) batch_interval: Duration,
+    pub(crate) start_height: BlockHeight,
+    pub(crate) end_height: Option<BlockHeight>,
+    pub(crate) pragmas: Vec<(String, String)>,
+    pub(crate) cache_size: Option<u64>,
+    pub(crate) optimize_interval: Option<Duration>,
+    pub(crate) retry_initial: Duration,
+    pub(crate) retry_max: Duration,
+    pub(crate) tx_data_limit: Option<usize>,
+    pub(crate) tx_data_blobs: bool,
+    pub(crate) index_txs: bool,
+    pub(crate) index_stakes: bool,
+    pub(crate) query_indexes: bool,
+    pub(crate) read_only: bool,
+    pub(crate) max_connections: usize,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) acquire_timeout: Option<Duration>,
+    pub(crate) busy_timeout: Option<Duration>,
+    pub(crate) mmap_size: Option<u64>,
+    pub(crate) on_connect: Vec<ConnectHook>,
+}
+
+type HookFn = dyn Fn(&rusqlite::Connection) -> rusqlite::Result<()> + Send + Sync;
+
+/// Something to run on every new connection.
+#[derive(Clone)]
+pub(crate) struct ConnectHook(pub Arc<HookFn>);
+
+impl Debug for ConnectHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConnectHook")
+    }
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        Self {
+            fetch_window: 16,
+            batch_size: 100,
+            batch_interval: Duration::from_secs(5),
+            start_height: BlockHeight(0),
+            end_height: None,
+            pragmas: vec![
+                ("journal_mode".into(), "WAL".into()),
+                ("synchronous".into(), "NORMAL".into()),
+            ],
+            cache_size: None,
+            optimize_interval: Some(Duration::from_secs(3600)),
+            retry_initial: Duration::from_secs(1),
+            retry_max: Duration::from_secs(1),
+            tx_data_limit: Some(1024),
+            tx_data_blobs: false,
+            index_txs: true,
+            index_stakes: true,
+            query_indexes: true,
+            read_only: false,
+            max_connections: 32,
+            idle_timeout: Some(Duration::from_secs(300)),
+            acquire_timeout: Some(Duration::from_secs(30)),
+            busy_timeout: None,
+            mmap_size: None,
+            on_connect: vec![],
+        }
+    }
+}
+
+impl IndexerConfig {
+    /// How many upcoming heights to fetch from the network concurrently while catching up. Heights are still committed strictly in order. At least 1.
+    pub fn fetch_window(mut self, window: usize) -> Self {
+        self.fetch_window = window.max(1);
+        self
+    }
+
+    /// While catching up, commit up to this many heights in one SQLite transaction. Once caught up, every height is committed on its own as soon as it arrives. 1 disables batching.
+    pub fn batch_size(mut self, size: usize) -> Self {
+        self.batch_size = size.max(1);
+        self
+    }
+
+    /// While catching up, commit at least this often, even if the batch isn't full.
+    pub fn batch_interval(mut self, interval: Duration) -> Self {
+        self.batch_interval = interval;
+        self
+    }
+
+    /// Where a fresh database starts indexing, such as a trusted checkpoint. Defaults to genesis. Coins created earlier are missing, so coin aggregates and balances that reach below this height fail with [crate::Error::BelowIndexedRange]. A database that already started somewhere keeps its start.
+    pub fn start_height(mut self, height: BlockHeight) -> Self {
+        self.start_height = height;
+        self
+    }
+
+    /// Stops indexing after this height. Defaults to following the chain forever.
+    pub fn end_height(mut self, height: BlockHeight) -> Self {
+        self.end_height = Some(height);
+        self
+    }
+
+    /// Sets a pragma on every connection, replacing any value set for it before. The value is pasted into SQL as-is. By default, `journal_mode` is `WAL` and `synchronous` is `NORMAL`.
+    pub fn pragma(mut self, name: &str, value: &str) -> Self {
+        self.pragmas.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.pragmas.push((name.into(), value.into()));
+        self
+    }
+
+    /// How much memory each connection may use to cache pages, in KiB. Defaults to SQLite's own default.
+    pub fn cache_size(mut self, kib: u64) -> Self {
+        self.cache_size = Some(kib);
+        self
+    }
+
+    /// How often to run `PRAGMA optimize` in the background. Defaults to hourly; None never does.
+    pub fn optimize_interval(mut self, interval: Option<Duration>) -> Self {
+        self.optimize_interval = interval;
+        self
+    }
+
+    /// How long the loop waits before checking the network again: `initial` normally, doubling with every consecutive failure up to `max`. Defaults to a steady second.
+    pub fn retry_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.retry_initial = initial;
+        self.retry_max = max.max(initial);
+        self
+    }
+
+    /// How many bytes of each transaction's data to keep. Defaults to the first kilobyte; None keeps everything.
+    pub fn tx_data_limit(mut self, limit: Option<usize>) -> Self {
+        self.tx_data_limit = limit;
+        self
+    }
+
+    /// Whether to also keep every transaction's full data in the `txblobs` table, which stores identical data only once. Queries then return full data no matter what [IndexerConfig::tx_data_limit] cuts off. Defaults to false.
+    pub fn tx_data_blobs(mut self, enabled: bool) -> Self {
+        self.tx_data_blobs = enabled;
+        self
+    }
+
+    /// Whether to fill the `txvars` and `txinputs` tables. Defaults to true.
+    pub fn index_txs(mut self, enabled: bool) -> Self {
+        self.index_txs = enabled;
+        self
+    }
+
+    /// Whether to fill the `stakes` table. Turning this off also skips fetching stakers from the network. Defaults to true.
+    pub fn index_stakes(mut self, enabled: bool) -> Self {
+        self.index_stakes = enabled;
+        self
+    }
+
+    /// Whether to maintain the secondary indexes that only speed up queries. Without them, syncing is faster and the database smaller, but most queries scan whole tables. Changing this creates or drops the indexes on startup. Defaults to true.
+    pub fn query_indexes(mut self, enabled: bool) -> Self {
+        self.query_indexes = enabled;
+        self
+    }
+
+    /// Opens the database read-only, for querying a database that another process indexes. Nothing is synced or migrated, and the database must already have the current schema. Defaults to false.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// The most SQLite connections to keep open at once. Once all are in use, queries wait for one to free up. An iterator holds its connection until it's dropped, so don't nest more iterators than this. Defaults to 32; at least 1.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Closes connections that have sat unused for this long. Defaults to five minutes; None keeps them open. At least one second.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout.map(|timeout| timeout.max(Duration::from_secs(1)));
+        self
+    }
+
+    /// How long a query waits for a pooled connection to free up before failing with [crate::Error::PoolTimeout]. Defaults to 30 seconds; None waits forever.
+    pub fn acquire_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.acquire_timeout = timeout;
+        self
+    }
+
+    /// How long a connection waits on a locked database before giving up with [crate::Error::Busy]. Defaults to SQLite's five seconds.
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    /// How much of the database to memory-map, in bytes. Defaults to SQLite's own default.
+    pub fn mmap_size(mut self, bytes: u64) -> Self {
+        self.mmap_size = Some(bytes);
+        self
+    }
+
+    /// Runs something on every new connection, after the other settings are applied. Hooks run in the order they were added.
+    pub fn on_connect(
+        mut self,
+        hook: impl Fn(&rusqlite::Connection) -> rusqlite::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.on_connect.push(ConnectHook(Arc::new(hook)));
+        self
+    }
+}
@@ -0,0 +1,104 @@
+use std::{fmt::Display, time::Duration};
+
+use melstructs::BlockHeight;
+
+/// Errors returned by the fallible (`try_`) methods.
+#[derive(Debug)]
+pub enum Error {
+    /// The database was busy or locked. Trying again later may succeed.
+    Busy(rusqlite::Error),
+    /// Any other SQLite error, such as a malformed statement or a failed disk. Trying again won't help.
+    Sqlite(rusqlite::Error),
+    /// Every pooled connection stayed in use for the whole acquire timeout. Trying again later may succeed, once connections are returned; note that iterators hold on to theirs until dropped.
+    PoolTimeout(Duration),
+    /// A row in the database couldn't be decoded.
+    Corrupt(String),
+    /// The database was written by a newer, incompatible version of this crate.
+    SchemaTooNew { found: usize, supported: usize },
+    /// The database needs migrating, but was opened read-only.
+    SchemaTooOld { found: usize, supported: usize },
+    /// The query reaches below the height indexing started from, so its answer would be incomplete.
+    BelowIndexedRange {
+        requested: BlockHeight,
+        start: BlockHeight,
+    },
+}
+
+impl Error {
+    /// Whether the same operation might succeed if retried.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Busy(_) | Error::PoolTimeout(_))
+    }
+
+    pub(crate) fn corrupt(what: &str, value: impl std::fmt::Debug) -> Self {
+        Error::Corrupt(format!("invalid {} {:?}", what, value))
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Busy(err) => write!(f, "database busy: {}", err),
+            Error::Sqlite(err) => write!(f, "database error: {}", err),
+            Error::PoolTimeout(timeout) => {
+                write!(f, "no database connection freed up within {:?}", timeout)
+            }
+            Error::Corrupt(msg) => write!(f, "corrupt row: {}", msg),
+            Error::SchemaTooNew { found, supported } => write!(
+                f,
+                "database has schema version {}, but this version of melblkidx only understands up to version {}",
+                found, supported
+            ),
+            Error::SchemaTooOld { found, supported } => write!(
+                f,
+                "database has schema version {}, but was opened read-only and can't be migrated to version {}",
+                found, supported
+            ),
+            Error::BelowIndexedRange { requested, start } => write!(
+                f,
+                "height {} is below the indexed range, which starts at {}",
+                requested, start
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Busy(err) | Error::Sqlite(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked,
+                    ..
+                },
+                _,
+            ) => Error::Busy(err),
+            rusqlite::Error::FromSqlConversionFailure(..)
+            | rusqlite::Error::InvalidColumnType(..)
+            | rusqlite::Error::IntegralValueOutOfRange(..) => Error::Corrupt(err.to_string()),
+            err => Error::Sqlite(err),
+        }
+    }
+}
+
+/// Retries something for as long as the database is busy. Other errors are returned, including running out of pooled connections: the caller may be holding the very connections it's waiting for.
+pub(crate) fn retry_transient<T>(mut clos: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    loop {
+        match clos() {
+            Err(err @ Error::Busy(_)) => {
+                log::warn!("retrying failed: {}", err);
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            res => return res,
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::ops::RangeBounds;
+
+use genawaiter::sync::Gen;
+use itertools::Itertools;
+use rusqlite::{types::Value, ToSql};
+
+use crate::{pool::Pool, Error};
+
+/// SQL conditions, all of which must hold, together with their positional parameters. Shared by the query builders. Parameters are owned values, so filters can be freely sent between threads.
+#[derive(Clone, Default)]
+pub(crate) struct Filters {
+    clauses: Vec<String>,
+    params: Vec<Value>,
+}
+
+impl Filters {
+    /// Adds an arbitrary condition, with one parameter for every `?` in it.
+    pub fn add_clause(
+        &mut self,
+        clause: impl Into<String>,
+        params: impl IntoIterator<Item = Value>,
+    ) {
+        self.clauses.push(clause.into());
+        self.params.extend(params);
+    }
+
+    pub fn add_eq(&mut self, field: &str, val: impl Into<Value>) {
+        self.add_clause(format!("{} == ?", field), [val.into()]);
+    }
+
+    pub fn add_range<T, U: Into<Value>>(
+        &mut self,
+        field: &str,
+        range: impl RangeBounds<T>,
+        f: impl Fn(&T) -> U,
+    ) {
+        match range.start_bound() {
+            std::ops::Bound::Included(v) => {
+                self.add_clause(format!("{} >= ?", field), [f(v).into()]);
+            }
+            std::ops::Bound::Excluded(v) => {
+                self.add_clause(format!("{} > ?", field), [f(v).into()]);
+            }
+            std::ops::Bound::Unbounded => {}
+        }
+
+        match range.end_bound() {
+            std::ops::Bound::Included(v) => {
+                self.add_clause(format!("{} <= ?", field), [f(v).into()]);
+            }
+            std::ops::Bound::Excluded(v) => {
+                self.add_clause(format!("{} < ?", field), [f(v).into()]);
+            }
+            std::ops::Bound::Unbounded => {}
+        }
+    }
+
+    /// Adds a condition that the field equals one of the values.
+    pub fn add_in<T: Into<Value>>(&mut self, field: &str, vals: impl IntoIterator<Item = T>) {
+        let params = vals.into_iter().map(|v| v.into()).collect_vec();
+        if params.is_empty() {
+            self.add_clause("0", []);
+        } else {
+            let placeholders = vec!["?"; params.len()].join(", ");
+            self.add_clause(format!("{} in ({})", field, placeholders), params);
+        }
+    }
+
+    /// Combines two sets of filters into one that matches whatever either of them matches.
+    pub fn or(self, other: Filters) -> Filters {
+        let mut combined = Filters::default();
+        combined.add_clause(
+            format!("(({}) or ({}))", self.sql(), other.sql()),
+            self.params.into_iter().chain(other.params),
+        );
+        combined
+    }
+
+    /// Turns the filters into one that matches exactly what they don't. Conditions that are unknown because of nulls count as not matching before being negated.
+    pub fn not(self) -> Filters {
+        let mut negated = Filters::default();
+        negated.add_clause(format!("not coalesce(({}), 0)", self.sql()), self.params);
+        negated
+    }
+
+    /// The condition to put after `where`.
+    pub fn sql(&self) -> String {
+        if self.clauses.is_empty() {
+            "1".into()
+        } else {
+            self.clauses.iter().join(" and ")
+        }
+    }
+
+    pub fn params(&self) -> Vec<&dyn ToSql> {
+        self.params.iter().map(|f| f as &dyn ToSql).collect_vec()
+    }
+
+    pub fn owned_params(&self) -> Vec<Value> {
+        self.params.clone()
+    }
+}
+
+/// Runs a query on a pooled connection, lazily decoding each row. The statement is prepared upfront, so a malformed query fails here rather than halfway through iteration.
+pub(crate) fn query_rows<T>(
+    pool: &Pool,
+    query: String,
+    params: Vec<Value>,
+    decode: fn(&rusqlite::Row) -> Result<T, Error>,
+) -> Result<impl Iterator<Item = Result<T, Error>>, Error> {
+    log::debug!("iter query: {:?}", query);
+    let conn = pool.get_conn()?;
+    conn.prepare_cached(&query)?;
+    let gen = Gen::new(move |co| async move {
+        let res: Result<(), Error> = async {
+            let mut stmt = conn.prepare_cached(&query)?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(params.iter()))?;
+            while let Some(row) = rows.next()? {
+                co.yield_(decode(row)).await;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = res {
+            co.yield_(Err(err)).await;
+        }
+    });
+    Ok(gen.into_iter())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    /// The `a`s of the rows matching the filters, in a table where one `b` is null.
+    fn matching(filters: &Filters) -> Vec<i64> {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch("create table t (a, b); insert into t values (1, NULL), (2, 5), (3, 6);")
+            .unwrap();
+        let mut stmt = db
+            .prepare(&format!(
+                "select a from t where {} order by a",
+                filters.sql()
+            ))
+            .unwrap();
+        let rows = stmt
+            .query_map(&filters.params()[..], |row| row.get(0))
+            .unwrap();
+        rows.map(|a| a.unwrap()).collect()
+    }
+
+    fn eq(field: &str, val: i64) -> Filters {
+        let mut filters = Filters::default();
+        filters.add_eq(field, val);
+        filters
+    }
+
+    #[test]
+    fn negates_unknown_as_unmatched() {
+        assert_eq!(matching(&Filters::default()), [1, 2, 3]);
+        assert_eq!(matching(&Filters::default().not()), [0; 0]);
+        // b == 5 is null for the first row, which must not make its negation null too
+        assert_eq!(matching(&eq("b", 5)), [2]);
+        assert_eq!(matching(&eq("b", 5).not()), [1, 3]);
+        assert_eq!(matching(&eq("b", 5).not().not()), [2]);
+
+        let mut both = eq("b", 5);
+        both.add_range("a", 2.., |a: &i64| *a);
+        assert_eq!(matching(&both), [2]);
+        assert_eq!(matching(&both.not()), [1, 3]);
+    }
+
+    #[test]
+    fn ors_with_params_in_order() {
+        let either = eq("a", 1).or(eq("b", 6));
+        assert_eq!(matching(&either), [1, 3]);
+        assert_eq!(matching(&either.not()), [2]);
+        // unknown on one side, false on the other
+        assert_eq!(matching(&eq("b", 5).or(eq("a", 3))), [2, 3]);
+        assert_eq!(matching(&eq("b", 5).or(eq("a", 3)).not()), [1]);
+    }
+
+    #[test]
+    fn matches_nothing_in_an_empty_list() {
+        let mut none = Filters::default();
+        none.add_in::<i64>("b", []);
+        assert_eq!(matching(&none), [0; 0]);
+        assert_eq!(matching(&none.not()), [1, 2, 3]);
+
+        let mut some = Filters::default();
+        some.add_in("b", [5, 6]);
+        assert_eq!(matching(&some), [2, 3]);
+        assert_eq!(matching(&some.not()), [1]);
+    }
+}
@@ -0,0 +1,261 @@
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+use melstructs::{Address, BlockHeight, CoinValue, Denom, TxHash};
+use rusqlite::params;
+
+use crate::{coinquery::parse_txhash, pool::Pool, Error, InvalidCursor};
+
+/// What one transaction did to an address's coins.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// The transaction, or for proposer rewards, the pseudo-transaction that created them.
+    pub txhash: TxHash,
+    pub height: BlockHeight,
+    /// The value of the coins the transaction sent to the address, per denomination.
+    pub received: BTreeMap<Denom, CoinValue>,
+    /// The value of the address's coins the transaction spent, per denomination.
+    pub sent: BTreeMap<Denom, CoinValue>,
+    /// How much the address's balance changed, per denomination: received minus sent.
+    pub net: BTreeMap<Denom, i128>,
+}
+
+/// A page of an address's history, with the cursor to fetch the next one from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// Where the next page starts. None once the history is exhausted; with a limit, a full page always has one, even if the next page turns out empty.
+    pub next: Option<HistoryCursor>,
+}
+
+/// An opaque position in an address's history, used to fetch the page after it. Convertible to and from a string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoryCursor {
+    height: u64,
+    txhash: TxHash,
+}
+
+impl From<&HistoryEntry> for HistoryCursor {
+    fn from(entry: &HistoryEntry) -> Self {
+        Self {
+            height: entry.height.0,
+            txhash: entry.txhash,
+        }
+    }
+}
+
+impl Display for HistoryCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut bytes = Vec::with_capacity(40);
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&self.txhash.0 .0);
+        f.write_str(&hex::encode(bytes))
+    }
+}
+
+impl FromStr for HistoryCursor {
+    type Err = InvalidCursor;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| InvalidCursor)?;
+        if bytes.len() != 40 {
+            return Err(InvalidCursor);
+        }
+        Ok(Self {
+            height: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            txhash: TxHash(tmelcrypt::HashVal(bytes[8..40].try_into().unwrap())),
+        })
+    }
+}
+
+/// A half-built query on the transactions touching an address, oldest first.
+#[derive(Clone)]
+pub struct AddressHistory {
+    pool: Pool,
+
+    address: Address,
+    after: Option<HistoryCursor>,
+    limit: Option<u64>,
+}
+
+impl AddressHistory {
+    pub(crate) fn new(pool: Pool, address: Address) -> Self {
+        Self {
+            pool,
+            address,
+            after: None,
+            limit: None,
+        }
+    }
+
+    /// Starts right after the given cursor.
+    pub fn after(mut self, cursor: HistoryCursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// Returns at most this many transactions.
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Fetches a page of history, ordered by height and then txhash. Spends of coins created before indexing started are missing, since their owner isn't known.
+    pub fn page(&self) -> Result<HistoryPage, Error> {
+        let (after_height, after_txhash) = match self.after {
+            Some(cursor) => (cursor.height as i64, cursor.txhash.to_string()),
+            // sorts before every height and txhash
+            None => (-1, String::new()),
+        };
+        let limit = self.limit.map_or(-1, |limit| limit as i64);
+        let conn = self.pool.get_conn()?;
+        // pages are made of whole transactions, so pick them first, then total up their coins
+        let mut stmt = conn.prepare_cached(
+            r"with flows as (
+                select create_txhash as txhash, create_height as height, 0 as sent, denom, value from coins where covhash = $1
+                union all
+                select spend_txhash, spend_height, 1, denom, value from coins where covhash = $1 and spend_txhash is not null
+            ), page as (
+                select distinct height, txhash from flows where (height, txhash) > ($2, $3) order by height, txhash limit $4
+            )
+            select height, txhash, sent, denom, u128_sum(value) from flows natural join page
+            group by height, txhash, sent, denom order by height, txhash",
+        )?;
+        let mut rows = stmt.query(params![
+            self.address.to_string(),
+            after_height,
+            after_txhash,
+            limit
+        ])?;
+        let mut entries: Vec<HistoryEntry> = vec![];
+        while let Some(row) = rows.next()? {
+            let height = BlockHeight(row.get(0)?);
+            let txhash = parse_txhash(&row.get::<_, String>(1)?)?;
+            let sent: bool = row.get(2)?;
+            let denom: Vec<u8> = row.get(3)?;
+            let denom = Denom::from_bytes(&denom).ok_or_else(|| Error::corrupt("denom", &denom))?;
+            let value = CoinValue(u128::from_be_bytes(row.get(4)?));
+            if entries.last().map(|e| e.txhash) != Some(txhash) {
+                entries.push(HistoryEntry {
+                    txhash,
+                    height,
+                    received: BTreeMap::new(),
+                    sent: BTreeMap::new(),
+                    net: BTreeMap::new(),
+                });
+            }
+            let entry = entries.last_mut().unwrap();
+            let net = entry.net.entry(denom).or_default();
+            if sent {
+                entry.sent.insert(denom, value);
+                *net -= value.0 as i128;
+            } else {
+                entry.received.insert(denom, value);
+                *net += value.0 as i128;
+            }
+        }
+        let next = match self.limit {
+            Some(limit) if entries.len() as u64 == limit => entries.last().map(HistoryCursor::from),
+            _ => None,
+        };
+        Ok(HistoryPage { entries, next })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testutil::{address, txhash, TestDb},
+        IndexerConfig,
+    };
+
+    fn entry(
+        tx: u8,
+        height: u64,
+        received: &[(Denom, u128)],
+        sent: &[(Denom, u128)],
+        net: &[(Denom, i128)],
+    ) -> HistoryEntry {
+        HistoryEntry {
+            txhash: txhash(tx),
+            height: BlockHeight(height),
+            received: received.iter().map(|(d, v)| (*d, CoinValue(*v))).collect(),
+            sent: sent.iter().map(|(d, v)| (*d, CoinValue(*v))).collect(),
+            net: net.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn pages_through_an_address_history() {
+        let db = TestDb::new(IndexerConfig::default());
+        // tx1 pays address 1 twice in MEL and once in SYM, and address 2 once
+        db.add_coin((1, 0, 1), 1, Denom::Mel, 10);
+        db.add_coin((1, 1, 1), 1, Denom::Sym, 4);
+        db.add_coin((1, 2, 1), 2, Denom::Mel, 7);
+        db.add_coin((1, 3, 1), 1, Denom::Mel, 1);
+        // tx2 spends from address 1 and pays it change; tx3 pays it at the same height
+        db.spend_coin((1, 0), (2, 0, 2));
+        db.add_coin((2, 0, 2), 1, Denom::Mel, 3);
+        db.add_coin((2, 1, 2), 2, Denom::Mel, 6);
+        db.add_coin((3, 0, 2), 1, Denom::Mel, 2);
+        // tx4 spends from address 1 in both denominations; tx5 doesn't touch it
+        db.spend_coin((1, 1), (4, 0, 3));
+        db.spend_coin((2, 0), (4, 1, 3));
+        db.add_coin((4, 0, 3), 2, Denom::Mel, 3);
+        db.spend_coin((1, 2), (5, 0, 4));
+        db.add_coin((5, 0, 4), 2, Denom::Mel, 7);
+
+        let expected = vec![
+            entry(
+                1,
+                1,
+                &[(Denom::Mel, 11), (Denom::Sym, 4)],
+                &[],
+                &[(Denom::Mel, 11), (Denom::Sym, 4)],
+            ),
+            entry(
+                2,
+                2,
+                &[(Denom::Mel, 3)],
+                &[(Denom::Mel, 10)],
+                &[(Denom::Mel, -7)],
+            ),
+            entry(3, 2, &[(Denom::Mel, 2)], &[], &[(Denom::Mel, 2)]),
+            entry(
+                4,
+                3,
+                &[],
+                &[(Denom::Mel, 3), (Denom::Sym, 4)],
+                &[(Denom::Mel, -3), (Denom::Sym, -4)],
+            ),
+        ];
+        let history = AddressHistory::new(db.pool().clone(), address(1));
+        assert_eq!(
+            history.page().unwrap(),
+            HistoryPage {
+                entries: expected.clone(),
+                next: None,
+            }
+        );
+
+        // a full page has a cursor even if nothing comes after it
+        let mut paged = vec![];
+        let mut page = history.clone().limit(2).page().unwrap();
+        loop {
+            assert!(page.entries.len() <= 2);
+            paged.extend(page.entries);
+            let next = match page.next {
+                Some(next) => next.to_string().parse().unwrap(),
+                None => break,
+            };
+            page = history.clone().limit(2).after(next).page().unwrap();
+        }
+        assert_eq!(paged, expected);
+
+        let page = history.clone().limit(3).page().unwrap();
+        assert_eq!(page.entries, expected[..3]);
+        let page = history.limit(3).after(page.next.unwrap()).page().unwrap();
+        assert_eq!(page.entries, expected[3..]);
+        assert_eq!(page.next, None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use event_listener::Event;
+
+/// Switches shared between an [crate::Indexer] and its background loop, used to pause, resume and stop it.
+#[derive(Default)]
+pub(crate) struct Lifecycle {
+    paused: AtomicBool,
+    stopping: AtomicBool,
+    changed: Event,
+}
+
+impl Lifecycle {
+    /// Asks the loop to pause after the height it's currently on.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+        self.changed.notify(usize::MAX);
+    }
+
+    /// Lets a paused loop continue.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.changed.notify(usize::MAX);
+    }
+
+    /// Asks the loop to stop for good.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.changed.notify(usize::MAX);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the loop is allowed to make progress. Returns false if it should stop instead.
+    pub async fn wait_runnable(&self) -> bool {
+        loop {
+            if self.is_stopping() {
+                return false;
+            }
+            if !self.is_paused() {
+                return true;
+            }
+            let listener = self.changed.listen();
+            // check again, in case we raced with a change before listening
+            if self.is_stopping() || !self.is_paused() {
+                continue;
+            }
+            listener.await;
+        }
+    }
+
+    /// Waits until the loop is asked to stop.
+    pub async fn wait_stop(&self) {
+        loop {
+            if self.is_stopping() {
+                return;
+            }
+            let listener = self.changed.listen();
+            if self.is_stopping() {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
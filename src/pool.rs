@@ -1,50 +1,148 @@
 use std::{
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{mpsc, Arc},
     time::{Duration, Instant},
 };
 
-use concurrent_queue::ConcurrentQueue;
+use event_listener::Event;
+use parking_lot::Mutex;
 
-use crate::repeat_fallible;
+use smol::future::FutureExt;
 
-/// A pool of SQLite connections
+use crate::{sqlfunc, Error, IndexerConfig};
+
+/// A bounded pool of SQLite connections. Once all of them are in use, callers wait for one to come back.
 #[derive(Clone)]
 pub struct Pool {
-    queue: Arc<ConcurrentQueue<rusqlite::Connection>>,
+    state: Arc<Mutex<PoolState>>,
+    /// Notified whenever a connection comes back, or room for a new one frees up
+    available: Arc<Event>,
     path: PathBuf,
+    config: Arc<IndexerConfig>,
+    stop_optimizer: Arc<Mutex<Option<mpsc::Sender<()>>>>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// Connections not in use, with when they were returned. The most recently used are at the back.
+    idle: Vec<(rusqlite::Connection, Instant)>,
+    /// Connections open, whether in use or idle
+    open: usize,
+    waiting: usize,
+    waits: u64,
+    total_wait: Duration,
+}
+
+/// A snapshot of how a connection pool is being used.
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    /// Connections currently lent out.
+    pub in_use: usize,
+    /// Connections open but not in use.
+    pub idle: usize,
+    /// The most connections that may be open at once.
+    pub max_size: usize,
+    /// Callers currently waiting for a connection.
+    pub waiting: usize,
+    /// How many times a caller had to wait for a connection.
+    pub waits: u64,
+    /// How long callers spent waiting for connections, in total.
+    pub total_wait: Duration,
+}
+
+/// Counts a caller as waiting for as long as it lives.
+struct Waiting<'a>(&'a Mutex<PoolState>);
+
+impl<'a> Waiting<'a> {
+    fn new(state: &'a Mutex<PoolState>) -> Self {
+        state.lock().waiting += 1;
+        Self(state)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.lock().waiting -= 1;
+    }
+}
+
+/// What a caller gets when asking the pool for a connection without waiting.
+enum Slot {
+    Idle(rusqlite::Connection),
+    /// Room for a new connection, which the caller must open
+    Vacant,
+    Full,
 }
 
 impl Pool {
     /// Opens a new pool of SQLite connections.
-    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+    pub fn open(path: impl AsRef<Path>, config: &IndexerConfig) -> rusqlite::Result<Self> {
         let path: PathBuf = path.as_ref().to_owned();
-        let flags: rusqlite::OpenFlags = rusqlite::OpenFlags::default();
-        let db = rusqlite::Connection::open_with_flags(&path, flags)?;
-        db.query_row("PRAGMA journal_mode = WAL;", [], |f| f.get::<_, String>(0))?;
-        db.execute("PRAGMA synchronous = NORMAL;", [])?;
-        let queue = Arc::new(ConcurrentQueue::unbounded());
-        queue.push(db).unwrap();
-
-        let toret = Self { queue, path };
-        {
+        let config = Arc::new(config.clone());
+        let db = open_conn(&path, &config)?;
+        let state = PoolState {
+            idle: vec![(db, Instant::now())],
+            open: 1,
+            ..Default::default()
+        };
+
+        let toret = Self {
+            state: Arc::new(Mutex::new(state)),
+            available: Default::default(),
+            path,
+            config: config.clone(),
+            stop_optimizer: Default::default(),
+        };
+        if let Some(timeout) = config.idle_timeout {
+            // An idle pool is never asked for connections, so something else has to close them. The thread stops once the pool is gone.
+            let state = Arc::downgrade(&toret.state);
+            let available = toret.available.clone();
+            let spawned = std::thread::Builder::new()
+                .name("blkidx-idle".into())
+                .spawn(move || loop {
+                    std::thread::sleep(timeout / 2);
+                    match state.upgrade() {
+                        Some(state) => close_expired(&mut state.lock(), timeout, &available),
+                        None => break,
+                    }
+                });
+            if let Err(e) = spawned {
+                log::warn!(
+                    "idle connections will only close when the pool is used: {:?}",
+                    e
+                );
+            }
+        }
+        if let Some(interval) = config.optimize_interval.filter(|_| !config.read_only) {
             loop {
-                let toret = toret.clone();
+                let pool = toret.clone();
+                // the optimizer stops once the sending half is used or dropped
+                let (send_stop, recv_stop) = mpsc::channel::<()>();
                 match std::thread::Builder::new()
                     .name("blkidx-optimize".into())
                     .spawn(move || loop {
                         log::info!("optimizing database...");
                         let start = Instant::now();
-                        let conn = toret.get_conn();
-                        let _ = conn
-                            .execute("PRAGMA optimize;", [])
-                            .map_err(|e| format!("error while optimizing database {:?}", e));
-                        log::info!("optimized in {:?}", start.elapsed());
-                        std::thread::sleep(Duration::from_secs(3600));
+                        match pool.get_conn() {
+                            Ok(conn) => {
+                                let _ = conn.execute("PRAGMA optimize;", []).map_err(|e| {
+                                    format!("error while optimizing database {:?}", e)
+                                });
+                                drop(conn);
+                                log::info!("optimized in {:?}", start.elapsed());
+                            }
+                            Err(err) => log::warn!("skipped optimizing database: {}", err),
+                        }
+                        if recv_stop.recv_timeout(interval) != Err(mpsc::RecvTimeoutError::Timeout)
+                        {
+                            log::debug!("optimizer stopped");
+                            break;
+                        }
                     }) {
                     Ok(_) => {
                         log::info!("successfully optimized database");
+                        *toret.stop_optimizer.lock() = Some(send_stop);
                         break;
                     }
                     Err(e) => log::warn!("failed to optimize database, trying again: {:?}", e),
@@ -55,32 +153,207 @@ impl Pool {
         Ok(toret)
     }
 
-    /// Obtains one connection from the pool.
-    pub fn get_conn(&self) -> impl DerefMut<Target = rusqlite::Connection> {
-        let conn = self.queue.pop().unwrap_or_else(|_| {
-            let flags: rusqlite::OpenFlags = rusqlite::OpenFlags::default();
-            repeat_fallible(|| {
-                let db = rusqlite::Connection::open_with_flags(&self.path, flags)?;
-                db.query_row("PRAGMA journal_mode = WAL;", [], |f| f.get::<_, String>(0))?;
-                db.execute("PRAGMA synchronous = NORMAL;", [])?;
-                anyhow::Ok(db)
-            })
-        });
-        WrappedConnection {
-            queue: self.queue.clone(),
+    /// Stops the background thread that periodically optimizes the database.
+    pub fn stop_optimizer(&self) {
+        if let Some(send_stop) = self.stop_optimizer.lock().take() {
+            let _ = send_stop.send(());
+        }
+    }
+
+    /// Obtains one connection from the pool, blocking while all of them are in use. Fails with [Error::PoolTimeout] if none frees up within the acquire timeout, or with the SQLite error if a new connection can't be opened.
+    pub fn get_conn(&self) -> Result<impl DerefMut<Target = rusqlite::Connection>, Error> {
+        let mut waiting_since = None;
+        loop {
+            if let Some(conn) = self.try_get_conn(waiting_since)? {
+                return Ok(conn);
+            }
+            let listener = self.available.listen();
+            // check again, in case a connection came back before we started listening
+            if let Some(conn) = self.try_get_conn(waiting_since)? {
+                return Ok(conn);
+            }
+            let since = *waiting_since.get_or_insert_with(Instant::now);
+            let _waiting = Waiting::new(&self.state);
+            match self.config.acquire_timeout {
+                Some(timeout) => {
+                    if !listener.wait_deadline(since + timeout) {
+                        return Err(Error::PoolTimeout(timeout));
+                    }
+                }
+                None => listener.wait(),
+            }
+        }
+    }
+
+    /// Obtains one connection from the pool, waiting asynchronously while all of them are in use. Fails with [Error::PoolTimeout] if none frees up within the acquire timeout, or with the SQLite error if a new connection can't be opened.
+    pub async fn get_conn_async(
+        &self,
+    ) -> Result<impl DerefMut<Target = rusqlite::Connection>, Error> {
+        let mut waiting_since = None;
+        loop {
+            if let Some(conn) = self.try_get_conn(waiting_since)? {
+                return Ok(conn);
+            }
+            let listener = self.available.listen();
+            if let Some(conn) = self.try_get_conn(waiting_since)? {
+                return Ok(conn);
+            }
+            let since = *waiting_since.get_or_insert_with(Instant::now);
+            // counts as waiting even if the future is dropped halfway
+            let _waiting = Waiting::new(&self.state);
+            let notified = async {
+                listener.await;
+                true
+            };
+            let expired = async {
+                match self.config.acquire_timeout {
+                    Some(timeout) => {
+                        smol::Timer::at(since + timeout).await;
+                        false
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            if !notified.or(expired).await {
+                return Err(Error::PoolTimeout(self.config.acquire_timeout.unwrap()));
+            }
+        }
+    }
+
+    /// Reports how the pool is being used.
+    pub fn stats(&self) -> PoolStats {
+        let state = self.state.lock();
+        PoolStats {
+            in_use: state.open - state.idle.len(),
+            idle: state.idle.len(),
+            max_size: self.config.max_connections,
+            waiting: state.waiting,
+            waits: state.waits,
+            total_wait: state.total_wait,
+        }
+    }
+
+    /// Gets a connection if one is idle or there's room to open one. If the caller had to wait, the wait is recorded in the stats. Fails if a new connection can't be opened.
+    fn try_get_conn(
+        &self,
+        waiting_since: Option<Instant>,
+    ) -> Result<Option<WrappedConnection>, Error> {
+        let slot = {
+            let mut state = self.state.lock();
+            self.close_expired(&mut state);
+            let slot = if let Some((conn, _)) = state.idle.pop() {
+                Slot::Idle(conn)
+            } else if state.open < self.config.max_connections {
+                state.open += 1;
+                Slot::Vacant
+            } else {
+                Slot::Full
+            };
+            if let (Some(since), false) = (waiting_since, matches!(slot, Slot::Full)) {
+                state.waits += 1;
+                state.total_wait += since.elapsed();
+            }
+            slot
+        };
+        let conn = match slot {
+            Slot::Idle(conn) => conn,
+            // opening can take a while, so it happens outside the lock
+            Slot::Vacant => match open_conn(&self.path, &self.config) {
+                Ok(conn) => conn,
+                Err(err) => {
+                    // give the room back, in case someone else has better luck
+                    self.state.lock().open -= 1;
+                    self.available.notify(1);
+                    return Err(err.into());
+                }
+            },
+            Slot::Full => return Ok(None),
+        };
+        Ok(Some(WrappedConnection {
+            pool: self.clone(),
             inner: Some(conn),
+        }))
+    }
+
+    /// Closes connections that have been idle for too long.
+    fn close_expired(&self, state: &mut PoolState) {
+        if let Some(timeout) = self.config.idle_timeout {
+            close_expired(state, timeout, &self.available);
         }
     }
+
+    fn put_conn(&self, conn: rusqlite::Connection) {
+        let mut state = self.state.lock();
+        state.idle.push((conn, Instant::now()));
+        self.close_expired(&mut state);
+        drop(state);
+        self.available.notify(1);
+    }
+}
+
+fn close_expired(state: &mut PoolState, timeout: Duration, available: &Event) {
+    // the least recently used come first
+    let expired = state
+        .idle
+        .iter()
+        .take_while(|(_, since)| since.elapsed() > timeout)
+        .count();
+    if expired > 0 {
+        log::debug!("closing {} idle connections", expired);
+        state.idle.drain(..expired);
+        state.open -= expired;
+        available.notify(expired);
+    }
+}
+
+fn open_conn(path: &Path, config: &IndexerConfig) -> rusqlite::Result<rusqlite::Connection> {
+    let flags = if config.read_only {
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+            | rusqlite::OpenFlags::SQLITE_OPEN_URI
+            | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX
+    } else {
+        rusqlite::OpenFlags::default()
+    };
+    let db = rusqlite::Connection::open_with_flags(path, flags)?;
+    for (name, value) in config.pragmas.iter() {
+        // the journal mode is a property of the database, which only writers may change
+        if config.read_only && name.eq_ignore_ascii_case("journal_mode") {
+            continue;
+        }
+        pragma(&db, name, value)?;
+    }
+    if let Some(kib) = config.cache_size {
+        pragma(&db, "cache_size", &format!("-{}", kib))?;
+    }
+    if let Some(timeout) = config.busy_timeout {
+        db.busy_timeout(timeout)?;
+    }
+    if let Some(bytes) = config.mmap_size {
+        pragma(&db, "mmap_size", &bytes.to_string())?;
+    }
+    sqlfunc::register(&db)?;
+    for hook in config.on_connect.iter() {
+        (hook.0)(&db)?;
+    }
+    Ok(db)
+}
+
+/// Sets a pragma, ignoring whatever rows it returns.
+fn pragma(db: &rusqlite::Connection, name: &str, value: &str) -> rusqlite::Result<()> {
+    let mut stmt = db.prepare(&format!("PRAGMA {} = {};", name, value))?;
+    let mut rows = stmt.query([])?;
+    while rows.next()?.is_some() {}
+    Ok(())
 }
 
 struct WrappedConnection {
-    queue: Arc<ConcurrentQueue<rusqlite::Connection>>,
+    pool: Pool,
     inner: Option<rusqlite::Connection>,
 }
 
 impl Drop for WrappedConnection {
     fn drop(&mut self) {
-        let _ = self.queue.push(self.inner.take().unwrap());
+        self.pool.put_conn(self.inner.take().unwrap());
     }
 }
 
@@ -96,3 +369,119 @@ impl DerefMut for WrappedConnection {
         self.inner.as_mut().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::testutil::TestDb;
+
+    fn one_connection() -> IndexerConfig {
+        IndexerConfig::default()
+            .max_connections(1)
+            .acquire_timeout(Some(Duration::from_millis(100)))
+    }
+
+    #[test]
+    fn times_out_while_every_connection_is_in_use() {
+        let db = TestDb::new(one_connection());
+        let held = db.pool().get_conn().unwrap();
+        assert!(matches!(
+            db.pool().get_conn(),
+            Err(Error::PoolTimeout(timeout)) if timeout == Duration::from_millis(100)
+        ));
+        assert!(matches!(
+            smol::block_on(db.pool().get_conn_async()),
+            Err(Error::PoolTimeout(_))
+        ));
+        let stats = db.pool().stats();
+        assert_eq!((stats.in_use, stats.idle, stats.waiting), (1, 0, 0));
+        // giving up isn't a wait that ended in a connection
+        assert_eq!(stats.waits, 0);
+
+        drop(held);
+        let stats = db.pool().stats();
+        assert_eq!((stats.in_use, stats.idle), (0, 1));
+    }
+
+    #[test]
+    fn waits_for_a_connection_to_come_back() {
+        let db = TestDb::new(one_connection());
+        let held = db.pool().get_conn().unwrap();
+        let returner = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(held);
+        });
+        let conn = db.pool().get_conn().unwrap();
+        returner.join().unwrap();
+        let stats = db.pool().stats();
+        assert_eq!((stats.in_use, stats.idle, stats.waiting), (1, 0, 0));
+        assert_eq!(stats.waits, 1);
+        assert!(stats.total_wait > Duration::ZERO);
+
+        drop(conn);
+        let stats = db.pool().stats();
+        assert_eq!((stats.in_use, stats.idle), (0, 1));
+    }
+
+    #[test]
+    fn stops_counting_abandoned_async_waits() {
+        let db = TestDb::new(one_connection().acquire_timeout(None));
+        let _held = db.pool().get_conn().unwrap();
+        smol::block_on(async {
+            let mut waiter = Box::pin(db.pool().get_conn_async());
+            assert!(smol::future::poll_once(&mut waiter).await.is_none());
+            assert_eq!(db.pool().stats().waiting, 1);
+        });
+        assert_eq!(db.pool().stats().waiting, 0);
+    }
+
+    #[test]
+    fn returns_errors_opening_connections() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let config = {
+            let failing = failing.clone();
+            one_connection().max_connections(2).on_connect(move |_| {
+                if failing.load(Ordering::SeqCst) {
+                    Err(rusqlite::Error::SqliteFailure(
+                        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CANTOPEN),
+                        None,
+                    ))
+                } else {
+                    Ok(())
+                }
+            })
+        };
+        let db = TestDb::new(config);
+        let _held = db.pool().get_conn().unwrap();
+        failing.store(true, Ordering::SeqCst);
+        assert!(matches!(db.pool().get_conn(), Err(Error::Sqlite(_))));
+        // the failed connection doesn't take up room
+        assert_eq!(db.pool().stats().in_use, 1);
+
+        failing.store(false, Ordering::SeqCst);
+        let _opened = db.pool().get_conn().unwrap();
+        assert_eq!(db.pool().stats().in_use, 2);
+    }
+
+    #[test]
+    fn closes_idle_connections_in_the_background() {
+        let db = TestDb::new(
+            IndexerConfig::default()
+                .max_connections(2)
+                .idle_timeout(Some(Duration::from_secs(1))),
+        );
+        let first = db.pool().get_conn().unwrap();
+        let second = db.pool().get_conn().unwrap();
+        drop((first, second));
+        assert_eq!(db.pool().stats().idle, 2);
+
+        std::thread::sleep(Duration::from_millis(2500));
+        let stats = db.pool().stats();
+        assert_eq!((stats.in_use, stats.idle), (0, 0));
+    }
+}
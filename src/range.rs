@@ -0,0 +1,35 @@
+use melstructs::BlockHeight;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::Error;
+
+/// The height indexing started from. Nothing below it is in the database. Absent if the indexer never ran.
+pub(crate) fn start_height(db: &Connection) -> Result<Option<BlockHeight>, Error> {
+    Ok(db
+        .query_row(
+            "select value from meta where key = 'start_height'",
+            [],
+            |row| Ok(BlockHeight(row.get(0)?)),
+        )
+        .optional()?)
+}
+
+/// Records where indexing starts, unless the database already started somewhere. Returns the start in effect.
+pub(crate) fn init_start_height(db: &Connection, start: BlockHeight) -> Result<BlockHeight, Error> {
+    db.execute(
+        "insert or ignore into meta values ('start_height', $1)",
+        params![start.0],
+    )?;
+    Ok(start_height(db)?.unwrap_or(start))
+}
+
+/// Fails if a query reaching down to the given height would miss heights below the indexed range.
+pub(crate) fn check_floor(db: &Connection, floor: u64) -> Result<(), Error> {
+    match start_height(db)? {
+        Some(start) if start.0 > floor => Err(Error::BelowIndexedRange {
+            requested: BlockHeight(floor),
+            start,
+        }),
+        _ => Ok(()),
+    }
+}
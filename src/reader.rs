@@ -0,0 +1,228 @@
+use std::{collections::BTreeMap, ops::RangeInclusive, path::Path};
+
+use melstructs::{Address, BlockHeight, CoinID, CoinValue, Denom, TxHash};
+use rusqlite::{params, OptionalExtension};
+use tmelcrypt::{Ed25519PK, HashVal};
+
+use crate::{
+    error::retry_transient,
+    pool::{Pool, PoolStats},
+    range, schema, trace, AddressHistory, CoinGraph, CoinQuery, Error, HeightInfo, IndexerConfig,
+    StakeQuery, TraceLimit, TxQuery,
+};
+
+/// A handle for querying an indexed database, without syncing it. An [crate::Indexer] derefs to one of these, so the same queries work on both.
+#[derive(Clone)]
+pub struct IndexReader {
+    pool: Pool,
+}
+
+impl IndexReader {
+    /// Opens a database that another process indexes, read-only. Queries see whatever the writer has committed, and wait out its commits rather than failing. The database must already have the current schema.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_with_config(path, IndexerConfig::default())
+    }
+
+    /// Opens a database read-only, like [IndexReader::open], with a custom configuration for its connections. Settings that only matter for syncing are ignored, and the database is opened read-only whatever the configuration says.
+    pub fn open_with_config(path: impl AsRef<Path>, config: IndexerConfig) -> Result<Self, Error> {
+        let pool = Pool::open(path, &config.read_only(true))?;
+        schema::check_version(&*pool.get_conn()?)?;
+        Ok(Self::new(pool))
+    }
+
+    pub(crate) fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    pub(crate) fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// Reports how the connection pool is being used.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// The heights indexed so far, from where indexing started to the highest committed height. None if nothing is committed yet.
+    pub fn indexed_range(&self) -> Result<Option<RangeInclusive<BlockHeight>>, Error> {
+        let conn = self.pool.get_conn()?;
+        let max_height: Option<u64> =
+            conn.query_row("select max(height) from headvars", [], |r| r.get(0))?;
+        let start_height = range::start_height(&conn)?.unwrap_or(BlockHeight(0));
+        Ok(max_height.map(|max_height| start_height..=BlockHeight(max_height)))
+    }
+
+    /// Creates an object for querying the coins
+    pub fn query_coins(&self) -> CoinQuery {
+        CoinQuery::new(self.pool.clone())
+    }
+
+    /// Creates an object for querying the transactions
+    pub fn query_txs(&self) -> TxQuery {
+        TxQuery::new(self.pool.clone())
+    }
+
+    /// What an address held at a height, per denomination, in one pass over its coins. Heights above the highest indexed one silently get the balances as of the highest indexed height instead; compare with [IndexReader::max_height] to tell. Fails with [Error::BelowIndexedRange] unless indexing started from genesis, since older coins are missing.
+    pub fn balances_at(
+        &self,
+        address: Address,
+        height: BlockHeight,
+    ) -> Result<BTreeMap<Denom, CoinValue>, Error> {
+        self.query_coins()
+            .covhash(address)
+            .unspent_by(height)
+            .group_by_denom()
+    }
+
+    /// Creates an object for paging through the transactions that sent coins to or spent coins from an address.
+    pub fn address_history(&self, address: Address) -> AddressHistory {
+        AddressHistory::new(self.pool.clone(), address)
+    }
+
+    /// Creates an object for querying the stakes
+    pub fn query_stakes(&self) -> StakeQuery {
+        StakeQuery::new(self.pool.clone())
+    }
+
+    /// Sums up the SYM staked by each public key in the given epoch.
+    pub fn stake_distribution(&self, epoch: u64) -> BTreeMap<Ed25519PK, CoinValue> {
+        retry_transient(|| self.try_stake_distribution(epoch)).unwrap()
+    }
+
+    /// Fallible version of [IndexReader::stake_distribution].
+    pub fn try_stake_distribution(
+        &self,
+        epoch: u64,
+    ) -> Result<BTreeMap<Ed25519PK, CoinValue>, Error> {
+        let mut distribution: BTreeMap<Ed25519PK, CoinValue> = BTreeMap::new();
+        for stake in self.query_stakes().epoch(epoch).try_iter()? {
+            let stake = stake?;
+            *distribution.entry(stake.pubkey).or_default() += stake.staked;
+        }
+        Ok(distribution)
+    }
+
+    /// Get miscellaneous info about a height
+    pub fn height_info(&self, height: BlockHeight) -> Option<HeightInfo> {
+        retry_transient(|| self.try_height_info(height)).unwrap()
+    }
+
+    /// Fallible version of [IndexReader::height_info].
+    pub fn try_height_info(&self, height: BlockHeight) -> Result<Option<HeightInfo>, Error> {
+        let conn = self.pool.get_conn()?;
+        let row = conn
+            .query_row(
+                "select * from headvars where height = $1",
+                params![height.0],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .optional()?;
+        row.map(|(blkhash, fee_pool, fee_multiplier, dosc_speed)| {
+            Ok(HeightInfo {
+                height,
+                blkhash: blkhash
+                    .parse()
+                    .map_err(|_| Error::corrupt("blkhash", &blkhash))?,
+                fee_pool: u128::from_be_bytes(fee_pool),
+                fee_multiplier: u128::from_be_bytes(fee_multiplier),
+                dosc_speed: u128::from_be_bytes(dosc_speed),
+            })
+        })
+        .transpose()
+    }
+
+    /// Get the max height
+    pub fn max_height(&self) -> BlockHeight {
+        retry_transient(|| self.try_max_height()).unwrap()
+    }
+
+    /// Fallible version of [IndexReader::max_height].
+    pub fn try_max_height(&self) -> Result<BlockHeight, Error> {
+        let conn = self.pool.get_conn()?;
+        Ok(conn.query_row(
+            "select coalesce(max(height), 0) from headvars",
+            params![],
+            |r| Ok(BlockHeight(r.get(0)?)),
+        )?)
+    }
+
+    /// Search for a transaction by hash. Returns the block in which it can be found.
+    pub fn txhash_to_height(&self, txhash: TxHash) -> Option<BlockHeight> {
+        retry_transient(|| self.try_txhash_to_height(txhash)).unwrap()
+    }
+
+    /// Fallible version of [IndexReader::txhash_to_height].
+    pub fn try_txhash_to_height(&self, txhash: TxHash) -> Result<Option<BlockHeight>, Error> {
+        let conn = self.pool.get_conn()?;
+        let height: Option<u64> = conn
+            .query_row(
+                "select height from txvars where txhash = $1",
+                params![txhash.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        if let Some(height) = height {
+            return Ok(Some(BlockHeight(height)));
+        }
+        // transactions indexed before heights were recorded, and that created no coins, can only be found through what they spent
+        let height: Option<u64> = conn
+            .query_row(
+                "select spend_height from coins where spend_txhash = $1 limit 1",
+                params![txhash.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(height.map(BlockHeight))
+    }
+
+    /// The coins a transaction spends, in input order. Complete even if some of the coins were created before indexing started. Empty if the transaction isn't indexed.
+    pub fn tx_inputs(&self, txhash: TxHash) -> Result<Vec<CoinID>, Error> {
+        trace::tx_inputs(&*self.pool.get_conn()?, txhash)
+    }
+
+    /// Traces where a coin's value came from: the transaction that created it, the coins that transaction spent, and so on, up to `max_depth` transactions back or until a proposer reward.
+    pub fn trace_ancestors(&self, coin: CoinID, max_depth: usize) -> Result<CoinGraph, Error> {
+        trace::trace_ancestors(&*self.pool.get_conn()?, coin, max_depth)
+    }
+
+    /// Traces where a coin's value went: the transaction that spent it, the coins that transaction created, and so on, within the limit.
+    pub fn trace_descendants(&self, coin: CoinID, limit: TraceLimit) -> Result<CoinGraph, Error> {
+        trace::trace_descendants(&*self.pool.get_conn()?, &[coin], limit)
+    }
+
+    /// Traces where everything ever sent to an address went, like [IndexReader::trace_descendants] from each of the coins it received.
+    pub fn trace_address_descendants(
+        &self,
+        address: Address,
+        limit: TraceLimit,
+    ) -> Result<CoinGraph, Error> {
+        let conn = self.pool.get_conn()?;
+        let coins = trace::received_coins(&conn, address)?;
+        trace::trace_descendants(&conn, &coins, limit)
+    }
+
+    /// Search for a block by hash.
+    pub fn blkhash_to_height(&self, blkhash: HashVal) -> Option<BlockHeight> {
+        retry_transient(|| self.try_blkhash_to_height(blkhash)).unwrap()
+    }
+
+    /// Fallible version of [IndexReader::blkhash_to_height].
+    pub fn try_blkhash_to_height(&self, blkhash: HashVal) -> Result<Option<BlockHeight>, Error> {
+        let conn = self.pool.get_conn()?;
+        Ok(conn
+            .query_row(
+                "select height from headvars where blkhash = $1",
+                params![blkhash.to_string()],
+                |row| Ok(BlockHeight(row.get(0)?)),
+            )
+            .optional()?)
+    }
+}
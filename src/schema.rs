@@ -0,0 +1,364 @@
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+
+use crate::Error;
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// All schema migrations, in order. Applying the migration at index `i` brings a database from schema version `i` to `i + 1`. Only ever append to this list.
+const MIGRATIONS: &[Migration] = &[
+    initial_tables,
+    txvars_inclusion,
+    stakes_by_pubkey,
+    indexed_range,
+    tx_data_blobs,
+    tx_inputs,
+];
+
+/// The schema version this version of the crate reads and writes.
+pub(crate) const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Secondary indexes that only speed up queries, by name and definition. Syncing works without them.
+const QUERY_INDEXES: &[(&str, &str)] = &[
+    ("coins_owner", "coins(covhash)"),
+    ("coins_balance", "coins(covhash, spend_txhash)"),
+    ("coins_balance1", "coins(covhash, spend_height)"),
+    ("coins_supply", "coins(create_height, spend_height)"),
+    ("coins_supply1", "coins(create_height, spend_txhash)"),
+    ("coins_denom", "coins(denom)"),
+    ("coins_spender", "coins(spend_txhash)"),
+    ("coins_createheight", "coins(create_height)"),
+    ("coins_spendheight", "coins(spend_height)"),
+    ("txvars_height", "txvars(height)"),
+    ("stakes_pubkey", "stakes(pubkey)"),
+    ("txinputs_coin", "txinputs(coin_txhash, coin_index)"),
+];
+
+/// Brings the database up to date, refusing to touch databases written by a newer, incompatible version. The version is tracked in `PRAGMA user_version`.
+pub(crate) fn migrate(db: &mut Connection) -> Result<(), Error> {
+    let txn = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: usize = txn.query_row("pragma user_version", [], |r| r.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(Error::SchemaTooNew {
+            found: version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("migrating database to schema version {}", i + 1);
+        migration(&txn)?;
+        txn.pragma_update(None, "user_version", i + 1)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Fails unless the database has exactly the schema this version of the crate understands. For read-only databases, which we can't migrate.
+pub(crate) fn check_version(db: &Connection) -> Result<(), Error> {
+    let version: usize = db.query_row("pragma user_version", [], |r| r.get(0))?;
+    match version.cmp(&SCHEMA_VERSION) {
+        std::cmp::Ordering::Less => Err(Error::SchemaTooOld {
+            found: version,
+            supported: SCHEMA_VERSION,
+        }),
+        std::cmp::Ordering::Greater => Err(Error::SchemaTooNew {
+            found: version,
+            supported: SCHEMA_VERSION,
+        }),
+        std::cmp::Ordering::Equal => Ok(()),
+    }
+}
+
+/// Creates or drops the indexes in [QUERY_INDEXES].
+pub(crate) fn set_query_indexes(db: &Connection, enabled: bool) -> Result<(), Error> {
+    for (name, definition) in QUERY_INDEXES {
+        if enabled {
+            db.execute(
+                &format!("create index if not exists {} on {}", name, definition),
+                [],
+            )?;
+        } else {
+            db.execute(&format!("drop index if exists {}", name), [])?;
+        }
+    }
+    Ok(())
+}
+
+/// The tables as they were before the schema was versioned. Databases from that era have version 0, so everything here must be idempotent.
+fn initial_tables(db: &Transaction) -> rusqlite::Result<()> {
+    db.execute(r"create table if not exists coins (create_txhash not null, create_index not null, create_height not null, spend_txhash, spend_index, spend_height, value not null, denom not null, covhash not null, additional_data not null,
+            UNIQUE(create_txhash, create_index, create_height) ON CONFLICT IGNORE
+        )
+        ", [])?;
+    db.execute(
+        r"create index if not exists coins_owner on coins(covhash)",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists coins_balance on coins(covhash, spend_txhash)",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists coins_balance1 on coins(covhash, spend_height)",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists coins_supply on coins(create_height, spend_height)",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists coins_supply1 on coins(create_height, spend_txhash)",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists coins_denom on coins(denom)",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists coins_spender on coins(spend_txhash)",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists coins_createheight on coins(create_height)",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists coins_spendheight on coins(spend_height)",
+        [],
+    )?;
+    db.execute(r"create table if not exists headvars (height primary key not null, blkhash not null, fee_pool not null, fee_multiplier not null, dosc_speed not null, UNIQUE(height) ON CONFLICT IGNORE
+        )
+        ", [])?;
+    db.execute(r"create table if not exists stakes (txhash primary key not null, pubkey not null, e_start not null, e_post_end not null, staked not null, UNIQUE(txhash) ON CONFLICT IGNORE
+        )
+        ", [])?;
+    db.execute(r"create table if not exists txvars (txhash primary key not null, kind not null, fee not null, covenants not null, data not null, sigs not null, UNIQUE(txhash) ON CONFLICT IGNORE
+        )
+        ", [])?;
+    Ok(())
+}
+
+/// Records the height and position of every transaction. Existing rows get their height from the coins they created, or failing that, the coins they spent.
+fn txvars_inclusion(db: &Transaction) -> rusqlite::Result<()> {
+    // unversioned databases may already have the columns
+    if !has_column(db, "txvars", "height")? {
+        log::info!("backfilling transaction heights...");
+        db.execute("alter table txvars add column height", [])?;
+        db.execute("alter table txvars add column position", [])?;
+        db.execute(
+            "update txvars set height = coalesce((select min(create_height) from coins where create_txhash = txvars.txhash), (select min(spend_height) from coins where spend_txhash = txvars.txhash))",
+            [],
+        )?;
+    }
+    db.execute(
+        r"create index if not exists txvars_height on txvars(height)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn stakes_by_pubkey(db: &Transaction) -> rusqlite::Result<()> {
+    db.execute(
+        r"create index if not exists stakes_pubkey on stakes(pubkey)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Adds a table for facts about the database as a whole, such as where indexing started. Existing databases started from their lowest height.
+fn indexed_range(db: &Transaction) -> rusqlite::Result<()> {
+    db.execute(
+        r"create table if not exists meta (key primary key not null, value not null)",
+        [],
+    )?;
+    db.execute(
+        r"insert or ignore into meta select 'start_height', min(height) from headvars having count(*) > 0",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Records how long transaction data really is, and adds a table for keeping it in full. Old rows get their length if they evidently weren't truncated.
+fn tx_data_blobs(db: &Transaction) -> rusqlite::Result<()> {
+    db.execute("alter table txvars add column data_len", [])?;
+    db.execute("alter table txvars add column data_hash", [])?;
+    db.execute(
+        "update txvars set data_len = length(data) where length(data) < 1024",
+        [],
+    )?;
+    db.execute(
+        r"create table if not exists txblobs (hash primary key not null, content not null)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Records every input of every transaction, whether or not the coin it spends is in `coins`. Existing rows come from the coins we saw spent.
+fn tx_inputs(db: &Transaction) -> rusqlite::Result<()> {
+    db.execute(r"create table if not exists txinputs (txhash not null, input_index not null, coin_txhash not null, coin_index not null, height not null, PRIMARY KEY(txhash, input_index) ON CONFLICT IGNORE
+        )
+        ", [])?;
+    log::info!("backfilling transaction inputs...");
+    db.execute(
+        "insert into txinputs select spend_txhash, spend_index, create_txhash, create_index, spend_height from coins where spend_txhash is not null",
+        [],
+    )?;
+    db.execute(
+        r"create index if not exists txinputs_coin on txinputs(coin_txhash, coin_index)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn has_column(db: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = db.prepare(&format!("pragma table_info({})", table))?;
+    let mut names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    names.try_fold(false, |found, name| Ok(found || name? == column))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{params, Connection};
+
+    use super::*;
+
+    fn txhash(n: u8) -> String {
+        hex::encode([n; 32])
+    }
+
+    /// A database as left by the versions before the schema was versioned.
+    fn baseline() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            r"create table coins (create_txhash not null, create_index not null, create_height not null, spend_txhash, spend_index, spend_height, value not null, denom not null, covhash not null, additional_data not null, UNIQUE(create_txhash, create_index, create_height) ON CONFLICT IGNORE);
+            create table headvars (height primary key not null, blkhash not null, fee_pool not null, fee_multiplier not null, dosc_speed not null, UNIQUE(height) ON CONFLICT IGNORE);
+            create table stakes (txhash primary key not null, pubkey not null, e_start not null, e_post_end not null, staked not null, UNIQUE(txhash) ON CONFLICT IGNORE);
+            create table txvars (txhash primary key not null, kind not null, fee not null, covenants not null, data not null, sigs not null, UNIQUE(txhash) ON CONFLICT IGNORE);",
+        )
+        .unwrap();
+        for height in [1u64, 2] {
+            db.execute(
+                "insert into headvars values ($1, $2, x'00', x'00', x'00')",
+                params![height, txhash(100 + height as u8)],
+            )
+            .unwrap();
+        }
+        // tx1 creates two coins, tx2 spends the first and creates another, tx3 spends that and creates nothing
+        let coins = [
+            (1, 0, 1, Some((2, 0, 2))),
+            (1, 1, 1, None),
+            (2, 0, 2, Some((3, 0, 2))),
+        ];
+        for (create_tx, create_index, create_height, spend) in coins {
+            db.execute(
+                "insert into coins values ($1, $2, $3, $4, $5, $6, x'00', x'6d', 'covhash', x'')",
+                params![
+                    txhash(create_tx),
+                    create_index,
+                    create_height,
+                    spend.map(|(tx, _, _)| txhash(tx)),
+                    spend.map(|(_, index, _)| index),
+                    spend.map(|(_, _, height)| height)
+                ],
+            )
+            .unwrap();
+        }
+        for (tx, data_len) in [(1u8, 3usize), (2, 1024), (3, 3)] {
+            db.execute(
+                "insert into txvars values ($1, 0, x'00', '[]', $2, '[]')",
+                params![txhash(tx), vec![0u8; data_len]],
+            )
+            .unwrap();
+        }
+        db
+    }
+
+    fn txinputs(db: &Connection) -> Vec<(String, u8, String, u8, u64)> {
+        let mut stmt = db
+            .prepare("select * from txinputs order by txhash, input_index")
+            .unwrap();
+        let rows = stmt
+            .query_map([], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?))
+            })
+            .unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn migrates_baseline() {
+        let mut db = baseline();
+        migrate(&mut db).unwrap();
+        let version: usize = db
+            .query_row("pragma user_version", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        // heights come from the coins a transaction created, or else the ones it spent
+        let height = |tx: u8| -> Option<u64> {
+            db.query_row(
+                "select height from txvars where txhash = $1",
+                [txhash(tx)],
+                |r| r.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(height(1), Some(1));
+        assert_eq!(height(2), Some(2));
+        assert_eq!(height(3), Some(2));
+        let data_len = |tx: u8| -> Option<usize> {
+            db.query_row(
+                "select data_len from txvars where txhash = $1",
+                [txhash(tx)],
+                |r| r.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(data_len(1), Some(3));
+        assert_eq!(data_len(2), None);
+        let start: u64 = db
+            .query_row(
+                "select value from meta where key = 'start_height'",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(start, 1);
+        assert_eq!(
+            txinputs(&db),
+            vec![
+                (txhash(2), 0, txhash(1), 0, 2),
+                (txhash(3), 0, txhash(2), 0, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn migrating_twice_does_nothing() {
+        let mut db = baseline();
+        migrate(&mut db).unwrap();
+        let inputs = txinputs(&db);
+        let total_changes = |db: &Connection| -> u64 {
+            db.query_row("select total_changes()", [], |r| r.get(0))
+                .unwrap()
+        };
+        let changes = total_changes(&db);
+        migrate(&mut db).unwrap();
+        assert_eq!(total_changes(&db), changes);
+        assert_eq!(txinputs(&db), inputs);
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(matches!(
+            migrate(&mut db),
+            Err(Error::SchemaTooNew { found, supported })
+                if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+        assert!(matches!(
+            check_version(&db),
+            Err(Error::SchemaTooNew { .. })
+        ));
+    }
+}
@@ -0,0 +1,64 @@
+use rusqlite::{
+    functions::{Aggregate, Context, FunctionFlags},
+    Connection,
+};
+
+/// Registers our custom SQL functions on a connection.
+pub(crate) fn register(db: &Connection) -> rusqlite::Result<()> {
+    db.create_aggregate_function(
+        "u128_sum",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        U128Sum,
+    )
+}
+
+/// `u128_sum(x)` adds up 16-byte big-endian blobs, the way values are stored, into another such blob. SQLite's own `sum` only handles 64-bit integers.
+struct U128Sum;
+
+impl Aggregate<u128, Vec<u8>> for U128Sum {
+    fn init(&self, _: &mut Context<'_>) -> rusqlite::Result<u128> {
+        Ok(0)
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, acc: &mut u128) -> rusqlite::Result<()> {
+        let val: [u8; 16] = ctx.get(0)?;
+        *acc = acc
+            .checked_add(u128::from_be_bytes(val))
+            .ok_or_else(|| rusqlite::Error::UserFunctionError("u128_sum overflowed".into()))?;
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context<'_>, acc: Option<u128>) -> rusqlite::Result<Vec<u8>> {
+        Ok(acc.unwrap_or_default().to_be_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sum(db: &Connection, values: &[u128]) -> rusqlite::Result<u128> {
+        db.execute("delete from vals", [])?;
+        for value in values {
+            db.execute("insert into vals values ($1)", [value.to_be_bytes()])?;
+        }
+        db.query_row("select u128_sum(v) from vals", [], |r| {
+            Ok(u128::from_be_bytes(r.get(0)?))
+        })
+    }
+
+    #[test]
+    fn sums_u128_blobs() {
+        let db = Connection::open_in_memory().unwrap();
+        register(&db).unwrap();
+        db.execute("create table vals (v)", []).unwrap();
+        assert_eq!(sum(&db, &[]).unwrap(), 0);
+        // well beyond what SQLite's own sum handles
+        assert_eq!(
+            sum(&db, &[u64::MAX as u128, u64::MAX as u128, 2]).unwrap(),
+            2 * u64::MAX as u128 + 2
+        );
+        assert!(sum(&db, &[u128::MAX, 1]).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::ops::{Bound, RangeBounds};
+
+use melstructs::{CoinValue, TxHash};
+use tmelcrypt::Ed25519PK;
+
+use crate::{
+    coinquery::parse_txhash,
+    error::retry_transient,
+    filter::{query_rows, Filters},
+    pool::Pool,
+    Error,
+};
+
+/// Info about a stake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StakeInfo {
+    pub txhash: TxHash,
+    pub pubkey: Ed25519PK,
+    pub e_start: u64,
+    /// The epoch *after* the last epoch in which the stake is effective.
+    pub e_post_end: u64,
+    pub staked: CoinValue,
+}
+
+/// A half-built query on the stakes table
+#[derive(Clone)]
+pub struct StakeQuery {
+    pool: Pool,
+
+    filters: Filters,
+}
+
+impl StakeQuery {
+    pub(crate) fn new(pool: Pool) -> Self {
+        Self {
+            pool,
+            filters: Filters::default(),
+        }
+    }
+
+    /// Adds a constraint on the staker's public key.
+    pub fn pubkey(mut self, pubkey: Ed25519PK) -> Self {
+        self.filters.add_eq("pubkey", pubkey.0.to_vec());
+        self
+    }
+
+    /// Adds a constraint that filters only for stakes effective in the given epoch.
+    pub fn epoch(mut self, epoch: u64) -> Self {
+        self.filters.add_range("e_start", ..=epoch, |f| *f as i64);
+        self.filters.add_range(
+            "e_post_end",
+            (Bound::Excluded(epoch), Bound::Unbounded),
+            |f| *f as i64,
+        );
+        self
+    }
+
+    /// Adds a constraint on the amount staked.
+    pub fn staked_range(mut self, range: impl RangeBounds<CoinValue>) -> Self {
+        self.filters
+            .add_range("staked", range, |f| f.0.to_be_bytes().to_vec());
+        self
+    }
+
+    /// Iterate through all the stakes matching this filter. Panics if the database can't be read.
+    pub fn iter(&self) -> impl Iterator<Item = StakeInfo> + '_ {
+        retry_transient(|| self.try_iter())
+            .unwrap()
+            .map(|stake| stake.unwrap())
+    }
+
+    /// Iterate through all the stakes matching this filter, returning errors instead of panicking.
+    pub fn try_iter(&self) -> Result<impl Iterator<Item = Result<StakeInfo, Error>> + '_, Error> {
+        query_rows(
+            &self.pool,
+            format!(
+                "select txhash, pubkey, e_start, e_post_end, staked from stakes where {}",
+                self.filters.sql()
+            ),
+            self.filters.owned_params(),
+            stake_from_row,
+        )
+    }
+}
+
+fn stake_from_row(row: &rusqlite::Row) -> Result<StakeInfo, Error> {
+    let txhash: String = row.get(0)?;
+    let txhash = parse_txhash(&txhash)?;
+    let pubkey = Ed25519PK(row.get(1)?);
+    let e_start: u64 = row.get(2)?;
+    let e_post_end: u64 = row.get(3)?;
+    let staked: CoinValue = u128::from_be_bytes(row.get(4)?).into();
+    Ok(StakeInfo {
+        txhash,
+        pubkey,
+        e_start,
+        e_post_end,
+        staked,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+    use crate::{
+        testutil::{txhash, TestDb},
+        IndexerConfig,
+    };
+
+    #[test]
+    fn matches_stakes_effective_in_an_epoch() {
+        let db = TestDb::new(IndexerConfig::default());
+        for (tx, e_start, e_post_end) in [(1, 5, 7), (2, 0, i64::MAX as u64)] {
+            db.pool()
+                .get_conn()
+                .unwrap()
+                .execute(
+                    "insert into stakes values ($1, $2, $3, $4, $5)",
+                    params![
+                        txhash(tx).to_string(),
+                        vec![tx; 32],
+                        e_start,
+                        e_post_end,
+                        100u128.to_be_bytes()
+                    ],
+                )
+                .unwrap();
+        }
+        let staked_in = |epoch| {
+            let mut stakes = StakeQuery::new(db.pool().clone())
+                .epoch(epoch)
+                .iter()
+                .map(|stake| stake.txhash)
+                .collect::<Vec<_>>();
+            stakes.sort();
+            stakes
+        };
+        assert_eq!(staked_in(4), [txhash(2)]);
+        assert_eq!(staked_in(5), [txhash(1), txhash(2)]);
+        assert_eq!(staked_in(6), [txhash(1), txhash(2)]);
+        assert_eq!(staked_in(7), [txhash(2)]);
+        assert_eq!(staked_in(i64::MAX as u64 - 1), [txhash(2)]);
+        assert_eq!(staked_in(i64::MAX as u64), []);
+        // used to overflow computing the epoch after it
+        assert_eq!(staked_in(u64::MAX), []);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use event_listener::Event;
+use melstructs::BlockHeight;
+use parking_lot::Mutex;
+
+/// How far back we look when computing the indexing rate.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// A snapshot of the indexer's progress.
+#[derive(Clone, Debug)]
+pub struct SyncStatus {
+    /// Highest height committed to the database.
+    pub indexed_height: BlockHeight,
+    /// Highest height the network reported, if we've heard from it yet.
+    pub network_height: Option<BlockHeight>,
+    /// Average indexing rate over the last few seconds.
+    pub blocks_per_second: f64,
+    /// The error that most recently made the loop restart.
+    pub last_error: Option<String>,
+    /// How many times in a row the loop failed since the last successful commit. Steadily growing means the indexer is stuck retrying.
+    pub consecutive_failures: u64,
+    /// When a height was last committed.
+    pub last_commit: Option<SystemTime>,
+}
+
+impl SyncStatus {
+    /// How many heights we are behind the network, if known.
+    pub fn blocks_behind(&self) -> Option<u64> {
+        self.network_height
+            .map(|h| h.0.saturating_sub(self.indexed_height.0))
+    }
+}
+
+/// Collects progress information from the indexer loop, and wakes up those waiting for heights.
+pub(crate) struct StatusTracker {
+    status: Mutex<SyncStatus>,
+    recent_commits: Mutex<VecDeque<Instant>>,
+    committed: Event,
+    /// Set once no more heights will be committed
+    finished: AtomicBool,
+}
+
+impl StatusTracker {
+    pub fn new(indexed_height: BlockHeight) -> Self {
+        Self {
+            status: Mutex::new(SyncStatus {
+                indexed_height,
+                network_height: None,
+                blocks_per_second: 0.0,
+                last_error: None,
+                consecutive_failures: 0,
+                last_commit: None,
+            }),
+            recent_commits: Default::default(),
+            committed: Event::new(),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the current status.
+    pub fn status(&self) -> SyncStatus {
+        let mut status = self.status.lock().clone();
+        let mut recent = self.recent_commits.lock();
+        trim_window(&mut recent);
+        status.blocks_per_second = recent.len() as f64 / RATE_WINDOW.as_secs_f64();
+        status
+    }
+
+    pub fn record_network_height(&self, height: BlockHeight) {
+        self.status.lock().network_height = Some(height);
+    }
+
+    pub fn record_commit(&self, height: BlockHeight) {
+        {
+            let mut status = self.status.lock();
+            status.indexed_height = status.indexed_height.max(height);
+            status.consecutive_failures = 0;
+            status.last_commit = Some(SystemTime::now());
+        }
+        let mut recent = self.recent_commits.lock();
+        recent.push_back(Instant::now());
+        trim_window(&mut recent);
+        drop(recent);
+        self.committed.notify(usize::MAX);
+    }
+
+    pub fn record_error(&self, err: &anyhow::Error) {
+        let mut status = self.status.lock();
+        status.last_error = Some(format!("{:#}", err));
+        status.consecutive_failures += 1;
+    }
+
+    /// Records that no more heights will be committed, waking up everyone still waiting for one.
+    pub fn record_finished(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+        self.committed.notify(usize::MAX);
+    }
+
+    /// Waits until the given height has been committed. Returns false if no more heights will be committed before then.
+    pub async fn wait_for_height(&self, height: BlockHeight) -> bool {
+        loop {
+            let listener = self.committed.listen();
+            if self.status.lock().indexed_height >= height {
+                return true;
+            }
+            if self.finished.load(Ordering::SeqCst) {
+                return false;
+            }
+            listener.await;
+        }
+    }
+}
+
+fn trim_window(recent: &mut VecDeque<Instant>) {
+    while recent
+        .front()
+        .map(|t| t.elapsed() > RATE_WINDOW)
+        .unwrap_or(false)
+    {
+        recent.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn stops_waiting_once_finished() {
+        let status = Arc::new(StatusTracker::new(BlockHeight(5)));
+        smol::block_on(async {
+            assert!(status.wait_for_height(BlockHeight(5)).await);
+            let waiter = smol::future::poll_once(status.wait_for_height(BlockHeight(6)));
+            assert_eq!(waiter.await, None);
+        });
+
+        let finisher = {
+            let status = status.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                status.record_commit(BlockHeight(6));
+                status.record_finished();
+            })
+        };
+        // woken up by the finish, not just checked at the start
+        assert!(!smol::block_on(status.wait_for_height(BlockHeight(7))));
+        finisher.join().unwrap();
+        assert!(smol::block_on(status.wait_for_height(BlockHeight(6))));
+    }
+}
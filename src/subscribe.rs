@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use melstructs::{CoinID, TxHash};
+use parking_lot::Mutex;
+use smol::channel::{Receiver, Sender, TrySendError};
+
+use crate::{CoinInfo, CoinSpendInfo, HeightInfo};
+
+/// How many blocks a subscriber may fall behind before it gets disconnected.
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+/// Everything committed at one height, as delivered by [crate::Indexer::subscribe].
+#[derive(Clone, Debug)]
+pub struct IndexedBlock {
+    pub info: HeightInfo,
+    /// Coins created at this height. Coins already spent within the same height have their spend info filled in.
+    pub created: Vec<CoinInfo>,
+    /// Coins spent at this height, and how they were spent.
+    pub spent: Vec<(CoinID, CoinSpendInfo)>,
+    /// Hashes of all the transactions in the block.
+    pub txhashes: Vec<TxHash>,
+}
+
+/// The set of live subscriptions.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    senders: Mutex<Vec<Sender<Arc<IndexedBlock>>>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&self) -> Receiver<Arc<IndexedBlock>> {
+        let (send, recv) = smol::channel::bounded(SUBSCRIBER_BACKLOG);
+        self.senders.lock().push(send);
+        recv
+    }
+
+    /// Whether anybody is listening, so that we can skip building events nobody will see.
+    pub fn is_empty(&self) -> bool {
+        self.senders.lock().is_empty()
+    }
+
+    /// Sends out a block to every subscriber. Subscribers that went away, or fell too far behind, are dropped; their streams end.
+    pub fn publish(&self, block: IndexedBlock) {
+        let block = Arc::new(block);
+        self.senders
+            .lock()
+            .retain(|send| match send.try_send(block.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("dropping a subscriber that fell too far behind");
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+    }
+}
@@ -0,0 +1,468 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Arc,
+    time::Instant,
+};
+
+use itertools::Itertools;
+use melprot::{Client, Snapshot};
+use melstructs::{Block, BlockHeight, CoinData, CoinID, StakeDoc, TxHash, TxKind};
+use rusqlite::{params, OptionalExtension, Transaction};
+use smol::future::FutureExt;
+use tmelcrypt::HashVal;
+
+use crate::{
+    lifecycle::Lifecycle, pool::Pool, status::StatusTracker, subscribe::Subscribers, verify,
+    CoinInfo, CoinSpendInfo, HeightInfo, IndexedBlock, IndexerConfig,
+};
+
+/// Everything fetched from the network about one height, ready to be committed.
+struct FetchedBlock {
+    height: BlockHeight,
+    snap: Snapshot,
+    blk: Block,
+    new_coins: HashMap<CoinID, CoinData>,
+    spent_coins: HashMap<CoinID, (TxHash, usize)>,
+    /// The stakers, if they changed since the previous height
+    stakes: Option<BTreeMap<HashVal, Vec<u8>>>,
+}
+
+pub(crate) async fn indexer_loop(
+    pool: Pool,
+    client: Client,
+    config: IndexerConfig,
+    lifecycle: Arc<Lifecycle>,
+    status: Arc<StatusTracker>,
+    subscribers: Arc<Subscribers>,
+) {
+    let mut failures: u32 = 0;
+    while lifecycle.wait_runnable().await {
+        // Stopping cancels whatever network request we're waiting on. Commits never contain an await point, so they can't be interrupted halfway.
+        let stopped = async {
+            lifecycle.wait_stop().await;
+            Ok(())
+        };
+        match indexer_loop_once(
+            pool.clone(),
+            client.clone(),
+            &config,
+            &lifecycle,
+            &status,
+            &subscribers,
+        )
+        .or(stopped)
+        .await
+        {
+            Ok(()) => failures = 0,
+            Err(err) => {
+                log::warn!("indexing failed with {:?}, restarting", err);
+                status.record_error(&err);
+                failures += 1;
+            }
+        }
+        // back off exponentially while failing
+        let delay = config
+            .retry_initial
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(config.retry_max);
+        async {
+            smol::Timer::after(delay).await;
+        }
+        .or(lifecycle.wait_stop())
+        .await;
+    }
+    status.record_finished();
+    log::debug!("indexer loop stopped");
+}
+
+async fn indexer_loop_once(
+    pool: Pool,
+    client: Client,
+    config: &IndexerConfig,
+    lifecycle: &Lifecycle,
+    status: &StatusTracker,
+    subscribers: &Subscribers,
+) -> anyhow::Result<()> {
+    // first, we find out the highest height we have, or where to start if we have nothing
+    let our_highest: u64 = pool.get_conn_async().await?.query_row(
+        "select coalesce(max(height), (select value from meta where key = 'start_height'), 0) from headvars",
+        [],
+        |d| d.get(0),
+    )?;
+    if let Some(end_height) = config.end_height {
+        if our_highest >= end_height.0 && committed_blkhash(&pool, our_highest).await?.is_some() {
+            log::info!("reached end height {}, no longer indexing", end_height);
+            status.record_finished();
+            lifecycle.wait_stop().await;
+            return Ok(());
+        }
+    }
+    // then find their highest
+    let highest_snap = client.latest_snapshot().await?;
+    let their_highest = highest_snap.current_header().height;
+    status.record_network_height(their_highest);
+    let last_height = config
+        .end_height
+        .map_or(their_highest, |end_height| end_height.min(their_highest));
+    // Every height we fetch must build on the one before it, starting from what's already committed
+    let mut previous_blkhash = match our_highest.checked_sub(1) {
+        Some(height) => committed_blkhash(&pool, height).await?,
+        None => None,
+    };
+    let redone_blkhash = committed_blkhash(&pool, our_highest).await?;
+    // Upcoming heights are fetched in the background, in a window ahead of the height being committed. Dropping the queue cancels the fetches.
+    let mut heights = (our_highest..=last_height.0).map(BlockHeight);
+    let mut fetching = VecDeque::new();
+    let mut last_stakes = None;
+    // While catching up, fetched heights pile up here until there are enough to be worth a commit
+    let mut batch = Vec::new();
+    let mut batch_start = Instant::now();
+    loop {
+        if lifecycle.is_paused() || lifecycle.is_stopping() {
+            commit_batch(&pool, config, status, subscribers, &mut batch).await?;
+        }
+        if !lifecycle.wait_runnable().await {
+            return Ok(());
+        }
+        let room = config.fetch_window - fetching.len();
+        fetching.extend(heights.by_ref().take(room).map(|height| {
+            let highest_snap = highest_snap.clone();
+            smolscale::spawn(async move { fetch_block(&highest_snap, height).await })
+        }));
+        let task = match fetching.pop_front() {
+            Some(task) => task,
+            None => return Ok(()),
+        };
+        let fetched = async {
+            let mut fetched = task.await?;
+            if let Some(previous) = previous_blkhash {
+                verify::verify_linkage(&fetched.blk, previous)?;
+            }
+            if fetched.height.0 == our_highest
+                && redone_blkhash.is_some_and(|h| h != fetched.blk.header.hash())
+            {
+                anyhow::bail!(
+                    "block {} conflicts with the one already committed",
+                    fetched.height
+                )
+            }
+            // Stakes are fetched in height order, since we only need them when they change
+            if config.index_stakes && last_stakes != Some(fetched.blk.header.stakes_hash) {
+                fetched.stakes = fetched
+                    .snap
+                    .get_raw()
+                    .get_stakers_raw(fetched.height)
+                    .await?;
+                if let Some(stakes) = &fetched.stakes {
+                    verify::verify_stakes(&fetched.blk.header, stakes)?;
+                }
+                last_stakes = Some(fetched.blk.header.stakes_hash);
+            }
+            previous_blkhash = Some(fetched.blk.header.hash());
+            anyhow::Ok(fetched)
+        };
+        let fetched = match fetched.await {
+            Ok(fetched) => fetched,
+            Err(err) => {
+                // keep what we already have
+                commit_batch(&pool, config, status, subscribers, &mut batch).await?;
+                return Err(err);
+            }
+        };
+        if batch.is_empty() {
+            batch_start = Instant::now();
+        }
+        batch.push(fetched);
+        // Nothing left to fetch means we've caught up, so there's no point in waiting
+        if fetching.is_empty()
+            || batch.len() >= config.batch_size
+            || batch_start.elapsed() >= config.batch_interval
+        {
+            commit_batch(&pool, config, status, subscribers, &mut batch).await?;
+        }
+    }
+}
+
+/// Commits a batch of fetched blocks in one transaction, then tells everyone about them. Leaves the batch empty.
+async fn commit_batch(
+    pool: &Pool,
+    config: &IndexerConfig,
+    status: &StatusTracker,
+    subscribers: &Subscribers,
+    batch: &mut Vec<FetchedBlock>,
+) -> anyhow::Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let mut conn = pool.get_conn_async().await?;
+    let conn = conn.transaction()?;
+    let fresh_heights = batch
+        .iter()
+        .map(|fetched| commit_block(&conn, config, fetched))
+        .collect::<anyhow::Result<Vec<bool>>>()?;
+    conn.commit()?;
+    log::trace!(
+        "committed {} heights up to {}",
+        batch.len(),
+        batch.last().unwrap().height
+    );
+    for (fetched, fresh_height) in batch.drain(..).zip(fresh_heights) {
+        status.record_commit(fetched.height);
+        if fresh_height && !subscribers.is_empty() {
+            subscribers.publish(indexed_block(fetched));
+        }
+    }
+    Ok(())
+}
+
+/// The hash of a committed height, if any.
+async fn committed_blkhash(pool: &Pool, height: u64) -> anyhow::Result<Option<HashVal>> {
+    let blkhash: Option<String> = pool
+        .get_conn_async()
+        .await?
+        .query_row(
+            "select blkhash from headvars where height = $1",
+            params![height],
+            |row| row.get(0),
+        )
+        .optional()?;
+    blkhash
+        .map(|blkhash| {
+            blkhash
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid blkhash {:?} at {}", blkhash, height))
+        })
+        .transpose()
+}
+
+/// Fetches a block and works out which coins it creates and spends.
+async fn fetch_block(highest_snap: &Snapshot, height: BlockHeight) -> anyhow::Result<FetchedBlock> {
+    let snap = highest_snap.get_older(height).await?;
+    // this already checks the transactions against the header's transactions root
+    let blk = snap.current_block().await?;
+    // get all the coins produced
+    let mut new_coins = HashMap::new();
+    let mut spent_coins = HashMap::new();
+    if let Some(cdh) = snap.get_coin(CoinID::proposer_reward(height)).await? {
+        new_coins.insert(CoinID::proposer_reward(height), cdh.coin_data);
+    }
+    for tx in blk.transactions.iter() {
+        for (i, output) in tx.outputs.iter().enumerate() {
+            new_coins.insert(CoinID::new(tx.hash_nosigs(), i as _), output.clone());
+        }
+
+        // Melswap transactions (Swap, LiqDeposit, LiqWithdrawal) have special rules.
+        // Swap: the *first output* of the transaction gets *transmuted* to something else, iff it hasn't been spent within the same block. e.g. a MEL output would magically turn into SYM, inside the coins mapping (but not in the outputs field of the transaction!)
+        // LiqDeposit: the FIRST TWO outputs of the transaction *magically disappear* iff it wasn't spent within the same block. It is replaced by one output, of the liquidity-token type. So outputs[0] turns into the liquidity token, and outputs[1] just poofs into thin air, as if it were spent by another transaction.
+        // LiqWithdraw: the first output of the transaction magically turns into the left-hand token of the wallet, and the second output magically into the right-hand token.
+
+        // We won't bother replicating the rules here. Instead, if we have melswap transactions that have outputs that aren't spent within this height, we just query the server to obtain the actual content of the outputs.
+
+        // We also may change this in the future, since esp. LiqDeposit and LiqWithdraw really break the consistency of the utxo graph.
+
+        if tx.kind == TxKind::Swap {
+            let id = CoinID::new(tx.hash_nosigs(), 0);
+            new_coins.remove(&id);
+            if let Some(coin) = snap.get_coin(id).await? {
+                new_coins.insert(id, coin.coin_data);
+            }
+        }
+
+        if tx.kind == TxKind::LiqDeposit {
+            // check 0 and 1
+            for output in 0..=1 {
+                let id = CoinID::new(tx.hash_nosigs(), output);
+                new_coins.remove(&id);
+                if let Some(coin) = snap.get_coin(id).await? {
+                    new_coins.insert(id, coin.coin_data);
+                }
+            }
+        }
+
+        if tx.kind == TxKind::LiqWithdraw {
+            // check 0 and 1
+            for output in 0..(tx.outputs.len() as u8 + 1) {
+                // 1 extra output inserted, lol
+                let id = CoinID::new(tx.hash_nosigs(), output);
+                new_coins.remove(&id);
+                if let Some(coin) = snap.get_coin(id).await? {
+                    new_coins.insert(id, coin.coin_data);
+                }
+            }
+        }
+
+        for (i, input) in tx.inputs.iter().enumerate() {
+            spent_coins.insert(*input, (tx.hash_nosigs(), i));
+        }
+    }
+    log::trace!("indexed {}", height);
+    Ok(FetchedBlock {
+        height,
+        snap,
+        blk,
+        new_coins,
+        spent_coins,
+        stakes: None,
+    })
+}
+
+/// Writes a fetched block within a transaction. Returns whether the height was new, rather than a redo of one that was already committed.
+fn commit_block(
+    conn: &Transaction,
+    config: &IndexerConfig,
+    fetched: &FetchedBlock,
+) -> anyhow::Result<bool> {
+    let FetchedBlock {
+        height,
+        blk,
+        new_coins,
+        spent_coins,
+        stakes,
+        ..
+    } = fetched;
+    for (new_coin, new_coindata) in new_coins.iter() {
+        conn.prepare_cached(
+            "insert into coins values ($1, $2, $3, NULL, NULL, NULL, $4, $5, $6, $7)",
+        )?
+        .execute(params![
+            new_coin.txhash.to_string(),
+            new_coin.index,
+            height.0,
+            new_coindata.value.0.to_be_bytes().to_vec(),
+            new_coindata.denom.to_bytes().to_vec(),
+            new_coindata.covhash.to_string(),
+            new_coindata.additional_data.to_vec()
+        ])?;
+    }
+    for (spent_coin, (spend_txhash, spend_idx)) in spent_coins.iter() {
+        conn.prepare_cached(
+            "update coins set spend_txhash = $1, spend_index = $2, spend_height = $3 where create_txhash = $4 and create_index = $5",
+        )?
+        .execute(params![
+            spend_txhash.to_string(),
+            spend_idx,
+            height.0,
+            spent_coin.txhash.to_string(),
+            spent_coin.index
+        ])?;
+    }
+    // update header variables. Nothing is inserted if we're redoing a height that was already committed.
+    let fresh_height = conn
+        .prepare_cached("insert into headvars values ($1, $2, $3, $4, $5)")?
+        .execute(params![
+            height.0,
+            blk.header.hash().to_string(),
+            blk.header.fee_pool.0.to_be_bytes(),
+            blk.header.fee_multiplier.to_be_bytes(),
+            blk.header.dosc_speed.to_be_bytes()
+        ])?
+        > 0;
+    // update stakers
+    if let Some(stakes) = stakes {
+        for (txhash, doc) in stakes {
+            let doc: StakeDoc = stdcode::deserialize(doc).unwrap();
+            conn.prepare_cached("insert into stakes values ($1, $2, $3, $4, $5)")?
+                .execute(params![
+                    txhash.to_string(),
+                    doc.pubkey.0.to_vec(),
+                    doc.e_start,
+                    doc.e_post_end,
+                    doc.syms_staked.0.to_be_bytes()
+                ])?;
+        }
+    }
+    if !config.index_txs {
+        return Ok(fresh_height);
+    }
+    // update transactions, recording their position in txhash order
+    for (position, txn) in blk
+        .transactions
+        .iter()
+        .sorted_by_key(|txn| txn.hash_nosigs())
+        .enumerate()
+    {
+        let data = match config.tx_data_limit {
+            Some(limit) => &txn.data[..txn.data.len().min(limit)],
+            None => &txn.data[..],
+        };
+        let data_hash = if config.tx_data_blobs {
+            let data_hash = tmelcrypt::hash_single(&txn.data).to_string();
+            conn.prepare_cached("insert or ignore into txblobs values ($1, $2)")?
+                .execute(params![data_hash, &txn.data[..]])?;
+            Some(data_hash)
+        } else {
+            None
+        };
+        conn.prepare_cached("insert into txvars values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)")?
+            .execute(params![
+                txn.hash_nosigs().to_string(),
+                u8::from(txn.kind),
+                txn.fee.0.to_be_bytes(),
+                serde_json::to_string(&txn.covenants.iter().map(hex::encode).collect_vec())
+                    .unwrap(),
+                data,
+                serde_json::to_string(&txn.sigs.iter().map(hex::encode).collect_vec()).unwrap(),
+                height.0,
+                position,
+                txn.data.len(),
+                data_hash
+            ])?;
+        for (input_index, input) in txn.inputs.iter().enumerate() {
+            conn.prepare_cached("insert into txinputs values ($1, $2, $3, $4, $5)")?
+                .execute(params![
+                    txn.hash_nosigs().to_string(),
+                    input_index,
+                    input.txhash.to_string(),
+                    input.index,
+                    height.0
+                ])?;
+        }
+    }
+    Ok(fresh_height)
+}
+
+/// What subscribers hear about a freshly committed block.
+fn indexed_block(fetched: FetchedBlock) -> IndexedBlock {
+    let FetchedBlock {
+        height,
+        blk,
+        new_coins,
+        spent_coins,
+        ..
+    } = fetched;
+    let spent: Vec<(CoinID, CoinSpendInfo)> = spent_coins
+        .iter()
+        .map(|(coin, (spend_txhash, spend_index))| {
+            (
+                *coin,
+                CoinSpendInfo {
+                    spend_txhash: *spend_txhash,
+                    spend_index: *spend_index,
+                    spend_height: height,
+                },
+            )
+        })
+        .collect();
+    let spend_infos: HashMap<CoinID, CoinSpendInfo> = spent.iter().copied().collect();
+    IndexedBlock {
+        info: HeightInfo {
+            height,
+            blkhash: blk.header.hash(),
+            fee_pool: blk.header.fee_pool.0,
+            fee_multiplier: blk.header.fee_multiplier,
+            dosc_speed: blk.header.dosc_speed,
+        },
+        created: new_coins
+            .into_iter()
+            .map(|(coin, coin_data)| CoinInfo {
+                create_txhash: coin.txhash,
+                create_index: coin.index,
+                create_height: height,
+                coin_data,
+                spend_info: spend_infos.get(&coin).copied(),
+            })
+            .collect(),
+        spent,
+        txhashes: blk.transactions.iter().map(|tx| tx.hash_nosigs()).collect(),
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use melstructs::{Address, BlockHeight, CoinID, Denom, TxHash, TxKind};
+use rusqlite::params;
+
+use crate::{pool::Pool, range, schema, IndexerConfig};
+
+/// A migrated database in a temporary file, filled in by hand. The file is deleted on drop.
+pub(crate) struct TestDb {
+    pool: Pool,
+    path: PathBuf,
+}
+
+impl TestDb {
+    /// Indexing starts at height 0. The optimizer never runs.
+    pub fn new(config: IndexerConfig) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "melblkidx-test-{}-{}.db",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let pool = Pool::open(&path, &config.optimize_interval(None)).unwrap();
+        {
+            let mut conn = pool.get_conn().unwrap();
+            schema::migrate(&mut conn).unwrap();
+            range::init_start_height(&conn, BlockHeight(0)).unwrap();
+        }
+        Self { pool, path }
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// Adds an unspent coin, created by transaction `txhash(tx)` at the given output index and height.
+    pub fn add_coin(
+        &self,
+        (tx, index, height): (u8, u8, u64),
+        owner: u8,
+        denom: Denom,
+        value: u128,
+    ) {
+        self.pool
+            .get_conn()
+            .unwrap()
+            .execute(
+                "insert into coins values ($1, $2, $3, NULL, NULL, NULL, $4, $5, $6, x'')",
+                params![
+                    txhash(tx).to_string(),
+                    index,
+                    height,
+                    value.to_be_bytes().to_vec(),
+                    denom.to_bytes().to_vec(),
+                    address(owner).to_string()
+                ],
+            )
+            .unwrap();
+    }
+
+    /// Records that transaction `txhash(tx)` spent a coin as the given input at the given height, whether or not the coin has a row.
+    pub fn spend_coin(
+        &self,
+        (coin_tx, coin_index): (u8, u8),
+        (tx, input_index, height): (u8, u8, u64),
+    ) {
+        let conn = self.pool.get_conn().unwrap();
+        conn.execute(
+            "update coins set spend_txhash = $1, spend_index = $2, spend_height = $3 where create_txhash = $4 and create_index = $5",
+            params![
+                txhash(tx).to_string(),
+                input_index,
+                height,
+                txhash(coin_tx).to_string(),
+                coin_index
+            ],
+        )
+        .unwrap();
+        conn.execute(
+            "insert into txinputs values ($1, $2, $3, $4, $5)",
+            params![
+                txhash(tx).to_string(),
+                input_index,
+                txhash(coin_tx).to_string(),
+                coin_index,
+                height
+            ],
+        )
+        .unwrap();
+    }
+
+    /// Adds a transaction with nothing but its kind and height.
+    pub fn add_tx(&self, tx: u8, kind: TxKind, height: u64) {
+        self.pool
+            .get_conn()
+            .unwrap()
+            .execute(
+                "insert into txvars (txhash, kind, fee, covenants, data, sigs, height) values ($1, $2, x'', '[]', x'', '[]', $3)",
+                params![txhash(tx).to_string(), u8::from(kind), height],
+            )
+            .unwrap();
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub(crate) fn txhash(n: u8) -> TxHash {
+    TxHash(tmelcrypt::HashVal([n; 32]))
+}
+
+pub(crate) fn address(n: u8) -> Address {
+    Address(tmelcrypt::HashVal([n; 32]))
+}
+
+pub(crate) fn coin(tx: u8, index: u8) -> CoinID {
+    CoinID {
+        txhash: txhash(tx),
+        index,
+    }
+}
@@ -0,0 +1,604 @@
+use std::collections::BTreeMap;
+
+use melstructs::{Address, BlockHeight, CoinID, CoinValue, Denom, TxHash, TxKind};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    coinquery::{coin_from_row, parse_txhash},
+    CoinInfo, Error,
+};
+
+/// Coins and the transactions linking them, found by tracing from a coin. Coins point to the transaction that created them through their ID, and transactions list the coins they spent.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoinGraph {
+    pub coins: BTreeMap<CoinID, CoinNode>,
+    pub txs: BTreeMap<TxHash, TxNode>,
+}
+
+impl CoinGraph {
+    /// Sums up the value of the coins at each depth, by denomination. Coins missing from the index count for nothing.
+    pub fn value_by_depth(&self) -> BTreeMap<usize, BTreeMap<Denom, CoinValue>> {
+        let mut values: BTreeMap<usize, BTreeMap<Denom, CoinValue>> = BTreeMap::new();
+        for node in self.coins.values() {
+            if let Some(info) = &node.info {
+                *values
+                    .entry(node.depth)
+                    .or_default()
+                    .entry(info.coin_data.denom)
+                    .or_default() += info.coin_data.value;
+            }
+        }
+        values
+    }
+}
+
+/// How far to trace. Unlimited by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceLimit {
+    pub(crate) depth: Option<usize>,
+    pub(crate) height: Option<BlockHeight>,
+}
+
+impl TraceLimit {
+    /// Follows at most this many transactions away from where tracing started.
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Doesn't follow transactions above this height.
+    pub fn height(mut self, height: BlockHeight) -> Self {
+        self.height = Some(height);
+        self
+    }
+}
+
+/// A coin reached by tracing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoinNode {
+    /// The coin's row in the index. Missing for coins created before indexing started, and for Melswap outputs spent in the block that created them.
+    pub info: Option<CoinInfo>,
+    /// How many transactions lie between this coin and the one tracing started from.
+    pub depth: usize,
+    pub origin: CoinOrigin,
+    pub fate: CoinFate,
+    /// Whether the coin is a Melswap output, which the swap transmutes after the fact. Its value then comes from the pool, not just the transaction's inputs.
+    pub transmuted: bool,
+}
+
+/// Where a traced coin came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoinOrigin {
+    /// Created by a transaction in [CoinGraph::txs].
+    Transaction,
+    /// A proposer reward, which no transaction created.
+    ProposerReward,
+    /// Created by a transaction that wasn't followed, because tracing reached its limit.
+    Unexplored,
+    /// Created by a transaction the index knows nothing about, such as one from before indexing started.
+    Unknown,
+}
+
+/// What became of a traced coin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoinFate {
+    /// Spent by a transaction in [CoinGraph::txs].
+    Transaction,
+    /// Not spent as of the highest indexed height.
+    Unspent,
+    /// Spent by a transaction that wasn't followed, because tracing reached its limit or went the other way.
+    Unexplored,
+    /// The coin isn't in the index, so there's no telling whether it was spent.
+    Unknown,
+}
+
+/// A transaction reached by tracing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxNode {
+    /// Missing if transactions aren't indexed.
+    pub kind: Option<TxKind>,
+    pub height: Option<BlockHeight>,
+    /// Every coin the transaction spent, in input order, whether or not tracing reached it.
+    pub inputs: Vec<CoinID>,
+    /// The transaction's outputs that tracing reached.
+    pub outputs: Vec<CoinID>,
+}
+
+/// Walks back from a coin through the transactions that funded it, until `max_depth` transactions away or a proposer reward.
+pub(crate) fn trace_ancestors(
+    conn: &Connection,
+    coin: CoinID,
+    max_depth: usize,
+) -> Result<CoinGraph, Error> {
+    let mut graph = CoinGraph::default();
+    graph.coins.insert(coin, coin_node(conn, coin, 0)?);
+    // breadth first, so every coin gets the depth of its shortest path
+    let mut frontier = vec![coin];
+    for depth in 0.. {
+        if frontier.is_empty() {
+            break;
+        }
+        let mut next = vec![];
+        for coin in frontier {
+            let node = graph.coins.get_mut(&coin).unwrap();
+            if node.origin == CoinOrigin::ProposerReward || depth == max_depth {
+                continue;
+            }
+            if let Some(tx) = graph.txs.get_mut(&coin.txhash) {
+                // another output of a transaction we already followed
+                node.origin = CoinOrigin::Transaction;
+                tx.outputs.push(coin);
+                continue;
+            }
+            let created = node.info.as_ref().map(|info| info.create_height);
+            let tx = match tx_node(conn, coin.txhash, created)? {
+                Some(tx) => tx,
+                None => {
+                    node.origin = CoinOrigin::Unknown;
+                    continue;
+                }
+            };
+            node.origin = CoinOrigin::Transaction;
+            for input in tx.inputs.iter() {
+                if !graph.coins.contains_key(input) {
+                    let mut input_node = coin_node(conn, *input, depth + 1)?;
+                    input_node.fate = CoinFate::Transaction;
+                    graph.coins.insert(*input, input_node);
+                    next.push(*input);
+                }
+            }
+            graph.txs.insert(
+                coin.txhash,
+                TxNode {
+                    outputs: vec![coin],
+                    ..tx
+                },
+            );
+        }
+        frontier = next;
+    }
+    Ok(graph)
+}
+
+/// Walks forward from some coins through the transactions that spent them, and the coins those created, within the limit. Each transaction is followed once, however many traced coins it spent.
+pub(crate) fn trace_descendants(
+    conn: &Connection,
+    coins: &[CoinID],
+    limit: TraceLimit,
+) -> Result<CoinGraph, Error> {
+    let mut graph = CoinGraph::default();
+    for coin in coins {
+        graph.coins.insert(*coin, coin_node(conn, *coin, 0)?);
+    }
+    let mut frontier = coins.to_vec();
+    for depth in 0.. {
+        if frontier.is_empty() {
+            break;
+        }
+        let mut next = vec![];
+        for coin in frontier {
+            let node = graph.coins.get_mut(&coin).unwrap();
+            let (spend_txhash, spend_height) = match node.info.as_ref().and_then(|i| i.spend_info) {
+                Some(spend_info) => (spend_info.spend_txhash, spend_info.spend_height),
+                None => match coin_spender(conn, coin)? {
+                    Some(spender) => spender,
+                    None => continue,
+                },
+            };
+            if limit.depth == Some(depth) || limit.height.is_some_and(|h| spend_height > h) {
+                node.fate = CoinFate::Unexplored;
+                continue;
+            }
+            node.fate = CoinFate::Transaction;
+            if graph.txs.contains_key(&spend_txhash) {
+                // another traced coin went into a transaction we already followed
+                continue;
+            }
+            let mut tx = tx_node(conn, spend_txhash, Some(spend_height))?
+                .expect("a transaction that spent a coin is known");
+            tx.outputs = tx_outputs(conn, spend_txhash)?;
+            for output in tx.outputs.iter() {
+                if let Some(output_node) = graph.coins.get_mut(output) {
+                    // tracing started from this coin too
+                    output_node.origin = CoinOrigin::Transaction;
+                    continue;
+                }
+                let mut output_node = coin_node(conn, *output, depth + 1)?;
+                output_node.origin = CoinOrigin::Transaction;
+                graph.coins.insert(*output, output_node);
+                next.push(*output);
+            }
+            graph.txs.insert(spend_txhash, tx);
+        }
+        frontier = next;
+    }
+    Ok(graph)
+}
+
+/// Every coin ever sent to an address.
+pub(crate) fn received_coins(conn: &Connection, address: Address) -> Result<Vec<CoinID>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "select create_txhash, create_index from coins where covhash = $1 order by create_height",
+    )?;
+    let mut rows = stmt.query(params![address.to_string()])?;
+    let mut coins = vec![];
+    while let Some(row) = rows.next()? {
+        coins.push(coin_id_from_row(row)?);
+    }
+    Ok(coins)
+}
+
+/// The coins a transaction spends, in input order.
+pub(crate) fn tx_inputs(conn: &Connection, txhash: TxHash) -> Result<Vec<CoinID>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "select coin_txhash, coin_index from txinputs where txhash = $1 order by input_index",
+    )?;
+    let mut rows = stmt.query(params![txhash.to_string()])?;
+    let mut inputs = vec![];
+    while let Some(row) = rows.next()? {
+        inputs.push(coin_id_from_row(row)?);
+    }
+    Ok(inputs)
+}
+
+/// The outputs of a transaction that we know of, whether from their own row or from what spent them.
+fn tx_outputs(conn: &Connection, txhash: TxHash) -> Result<Vec<CoinID>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "select create_txhash, create_index from coins where create_txhash = $1 union select coin_txhash, coin_index from txinputs where coin_txhash = $1 order by 2",
+    )?;
+    let mut rows = stmt.query(params![txhash.to_string()])?;
+    let mut outputs = vec![];
+    while let Some(row) = rows.next()? {
+        outputs.push(coin_id_from_row(row)?);
+    }
+    Ok(outputs)
+}
+
+/// The transaction that spent a coin, and its height, for coins without a row of their own.
+fn coin_spender(conn: &Connection, coin: CoinID) -> Result<Option<(TxHash, BlockHeight)>, Error> {
+    let row: Option<(String, u64)> = conn
+        .prepare_cached(
+            "select txhash, height from txinputs where coin_txhash = $1 and coin_index = $2",
+        )?
+        .query_row(params![coin.txhash.to_string(), coin.index], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .optional()?;
+    row.map(|(txhash, height)| Ok((parse_txhash(&txhash)?, BlockHeight(height))))
+        .transpose()
+}
+
+/// Looks up a coin, before following where it came from.
+fn coin_node(conn: &Connection, coin: CoinID, depth: usize) -> Result<CoinNode, Error> {
+    let mut stmt =
+        conn.prepare_cached("select * from coins where create_txhash = $1 and create_index = $2")?;
+    let info = stmt
+        .query(params![coin.txhash.to_string(), coin.index])?
+        .next()?
+        .map(coin_from_row)
+        .transpose()?;
+    let origin = match &info {
+        Some(info) if CoinID::proposer_reward(info.create_height) == coin => {
+            CoinOrigin::ProposerReward
+        }
+        _ => CoinOrigin::Unexplored,
+    };
+    let fate = match &info {
+        Some(info) if info.spend_info.is_none() => CoinFate::Unspent,
+        Some(_) => CoinFate::Unexplored,
+        None => CoinFate::Unknown,
+    };
+    // Mirrors the Melswap special cases in the sync loop
+    let transmuted = match tx_kind_height(conn, coin.txhash)?.map(|(kind, _)| kind) {
+        Some(TxKind::Swap) => coin.index == 0,
+        Some(TxKind::LiqDeposit) => coin.index <= 1,
+        Some(TxKind::LiqWithdraw) => true,
+        _ => false,
+    };
+    Ok(CoinNode {
+        info,
+        depth,
+        origin,
+        fate,
+        transmuted,
+    })
+}
+
+/// Looks up a transaction and what it spent, given the height it's known to be at, if any. None if the index knows nothing about it.
+fn tx_node(
+    conn: &Connection,
+    txhash: TxHash,
+    known_height: Option<BlockHeight>,
+) -> Result<Option<TxNode>, Error> {
+    let kind_height = tx_kind_height(conn, txhash)?;
+    let mut inputs = tx_inputs(conn, txhash)?;
+    if inputs.is_empty() {
+        // inputs aren't recorded if transactions aren't indexed, but the coins they spent still point to them
+        let mut stmt = conn.prepare_cached(
+            "select create_txhash, create_index from coins where spend_txhash = $1 order by spend_index",
+        )?;
+        let mut rows = stmt.query(params![txhash.to_string()])?;
+        while let Some(row) = rows.next()? {
+            inputs.push(coin_id_from_row(row)?);
+        }
+    }
+    if kind_height.is_none() && inputs.is_empty() && known_height.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(TxNode {
+        kind: kind_height.map(|(kind, _)| kind),
+        height: kind_height.and_then(|(_, height)| height).or(known_height),
+        inputs,
+        outputs: vec![],
+    }))
+}
+
+fn tx_kind_height(
+    conn: &Connection,
+    txhash: TxHash,
+) -> Result<Option<(TxKind, Option<BlockHeight>)>, Error> {
+    let row: Option<(u8, Option<u64>)> = conn
+        .prepare_cached("select kind, height from txvars where txhash = $1")?
+        .query_row(params![txhash.to_string()], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .optional()?;
+    row.map(|(kind, height)| {
+        let kind = TxKind::try_from(kind).map_err(|_| Error::corrupt("transaction kind", kind))?;
+        Ok((kind, height.map(BlockHeight)))
+    })
+    .transpose()
+}
+
+fn coin_id_from_row(row: &rusqlite::Row) -> Result<CoinID, Error> {
+    Ok(CoinID {
+        txhash: parse_txhash(&row.get::<_, String>(0)?)?,
+        index: row.get(1)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testutil::{address, coin, txhash, TestDb},
+        IndexerConfig,
+    };
+
+    fn summary(graph: &CoinGraph) -> BTreeMap<CoinID, (usize, CoinOrigin, CoinFate)> {
+        graph
+            .coins
+            .iter()
+            .map(|(id, node)| (*id, (node.depth, node.origin, node.fate)))
+            .collect()
+    }
+
+    fn mel(value: u128) -> BTreeMap<Denom, CoinValue> {
+        [(Denom::Mel, CoinValue(value))].into()
+    }
+
+    #[test]
+    fn traces_ancestors_breadth_first() {
+        let db = TestDb::new(IndexerConfig::default());
+        // tx2 spends a proposer reward and tx1's coin; tx3 and tx4 spend its outputs, one directly and one through tx3
+        let reward = CoinID::proposer_reward(BlockHeight(1));
+        db.pool()
+            .get_conn()
+            .unwrap()
+            .execute(
+                "insert into coins values ($1, $2, 1, $3, 0, 2, $4, $5, $6, x'')",
+                params![
+                    reward.txhash.to_string(),
+                    reward.index,
+                    txhash(2).to_string(),
+                    20u128.to_be_bytes().to_vec(),
+                    Denom::Mel.to_bytes().to_vec(),
+                    address(1).to_string()
+                ],
+            )
+            .unwrap();
+        db.pool()
+            .get_conn()
+            .unwrap()
+            .execute(
+                "insert into txinputs values ($1, 0, $2, $3, 2)",
+                params![
+                    txhash(2).to_string(),
+                    reward.txhash.to_string(),
+                    reward.index
+                ],
+            )
+            .unwrap();
+        db.add_coin((1, 0, 1), 1, Denom::Mel, 10);
+        db.spend_coin((1, 0), (2, 1, 2));
+        db.add_coin((2, 0, 2), 1, Denom::Mel, 12);
+        db.add_coin((2, 1, 2), 1, Denom::Mel, 18);
+        db.spend_coin((2, 0), (3, 0, 3));
+        db.add_coin((3, 0, 3), 1, Denom::Mel, 12);
+        // tx4 is a swap, and also spends a coin from before indexing started
+        db.add_tx(4, TxKind::Swap, 4);
+        db.spend_coin((3, 0), (4, 0, 4));
+        db.spend_coin((2, 1), (4, 1, 4));
+        db.spend_coin((9, 0), (4, 2, 4));
+        db.add_coin((4, 0, 4), 1, Denom::Mel, 40);
+        let conn = db.pool().get_conn().unwrap();
+
+        let graph = trace_ancestors(&conn, coin(4, 0), 10).unwrap();
+        assert_eq!(
+            summary(&graph),
+            BTreeMap::from([
+                (
+                    reward,
+                    (2, CoinOrigin::ProposerReward, CoinFate::Transaction)
+                ),
+                (
+                    coin(1, 0),
+                    (2, CoinOrigin::Transaction, CoinFate::Transaction)
+                ),
+                // also reachable through tx3, but tx4 spent it directly
+                (
+                    coin(2, 1),
+                    (1, CoinOrigin::Transaction, CoinFate::Transaction)
+                ),
+                (
+                    coin(2, 0),
+                    (2, CoinOrigin::Transaction, CoinFate::Transaction)
+                ),
+                (
+                    coin(3, 0),
+                    (1, CoinOrigin::Transaction, CoinFate::Transaction)
+                ),
+                (coin(4, 0), (0, CoinOrigin::Transaction, CoinFate::Unspent)),
+                (coin(9, 0), (1, CoinOrigin::Unknown, CoinFate::Transaction)),
+            ])
+        );
+        assert!(graph.coins[&coin(9, 0)].info.is_none());
+        assert!(graph.coins[&coin(4, 0)].transmuted);
+        assert!(!graph.coins[&coin(3, 0)].transmuted);
+        assert_eq!(
+            graph.txs.keys().copied().collect::<Vec<_>>(),
+            [txhash(1), txhash(2), txhash(3), txhash(4)]
+        );
+        let tx2 = &graph.txs[&txhash(2)];
+        assert_eq!(tx2.inputs, [reward, coin(1, 0)]);
+        assert_eq!(tx2.outputs, [coin(2, 1), coin(2, 0)]);
+        assert_eq!(tx2.height, Some(BlockHeight(2)));
+        let tx4 = &graph.txs[&txhash(4)];
+        assert_eq!(tx4.kind, Some(TxKind::Swap));
+        assert_eq!(tx4.inputs, [coin(3, 0), coin(2, 1), coin(9, 0)]);
+        assert_eq!(
+            graph.value_by_depth(),
+            [(0, mel(40)), (1, mel(30)), (2, mel(42))].into()
+        );
+
+        let graph = trace_ancestors(&conn, coin(4, 0), 1).unwrap();
+        assert_eq!(
+            summary(&graph),
+            BTreeMap::from([
+                (
+                    coin(2, 1),
+                    (1, CoinOrigin::Unexplored, CoinFate::Transaction)
+                ),
+                (
+                    coin(3, 0),
+                    (1, CoinOrigin::Unexplored, CoinFate::Transaction)
+                ),
+                (coin(4, 0), (0, CoinOrigin::Transaction, CoinFate::Unspent)),
+                (
+                    coin(9, 0),
+                    (1, CoinOrigin::Unexplored, CoinFate::Transaction)
+                ),
+            ])
+        );
+        assert_eq!(graph.txs.keys().copied().collect::<Vec<_>>(), [txhash(4)]);
+    }
+
+    #[test]
+    fn traces_descendants_within_limits() {
+        let db = TestDb::new(IndexerConfig::default());
+        // tx2 splits address 1's coin in two, which tx3 and tx4 spend; tx4 is a withdrawal whose second output tx5 spent in the same block
+        db.add_coin((1, 0, 1), 1, Denom::Mel, 10);
+        db.spend_coin((1, 0), (2, 0, 2));
+        db.add_coin((2, 0, 2), 1, Denom::Mel, 4);
+        db.add_coin((2, 1, 2), 2, Denom::Mel, 6);
+        db.spend_coin((2, 0), (3, 0, 3));
+        db.add_coin((3, 0, 3), 3, Denom::Mel, 4);
+        db.add_tx(4, TxKind::LiqWithdraw, 4);
+        db.spend_coin((2, 1), (4, 0, 4));
+        db.add_coin((4, 0, 4), 2, Denom::Sym, 5);
+        db.spend_coin((4, 1), (5, 0, 4));
+        db.add_coin((5, 0, 4), 2, Denom::Mel, 7);
+        let conn = db.pool().get_conn().unwrap();
+
+        let graph = trace_descendants(&conn, &[coin(1, 0)], TraceLimit::default()).unwrap();
+        assert_eq!(
+            summary(&graph),
+            BTreeMap::from([
+                (
+                    coin(1, 0),
+                    (0, CoinOrigin::Unexplored, CoinFate::Transaction)
+                ),
+                (
+                    coin(2, 0),
+                    (1, CoinOrigin::Transaction, CoinFate::Transaction)
+                ),
+                (
+                    coin(2, 1),
+                    (1, CoinOrigin::Transaction, CoinFate::Transaction)
+                ),
+                (coin(3, 0), (2, CoinOrigin::Transaction, CoinFate::Unspent)),
+                (coin(4, 0), (2, CoinOrigin::Transaction, CoinFate::Unspent)),
+                // only known from what spent it
+                (
+                    coin(4, 1),
+                    (2, CoinOrigin::Transaction, CoinFate::Transaction)
+                ),
+                (coin(5, 0), (3, CoinOrigin::Transaction, CoinFate::Unspent)),
+            ])
+        );
+        assert!(graph.coins[&coin(4, 1)].info.is_none());
+        assert!(graph.coins[&coin(4, 0)].transmuted && graph.coins[&coin(4, 1)].transmuted);
+        assert!(!graph.coins[&coin(3, 0)].transmuted);
+        let tx4 = &graph.txs[&txhash(4)];
+        assert_eq!(tx4.kind, Some(TxKind::LiqWithdraw));
+        assert_eq!(tx4.inputs, [coin(2, 1)]);
+        assert_eq!(tx4.outputs, [coin(4, 0), coin(4, 1)]);
+        assert_eq!(graph.txs[&txhash(5)].height, Some(BlockHeight(4)));
+        assert_eq!(
+            graph.value_by_depth(),
+            [
+                (0, mel(10)),
+                (1, mel(10)),
+                (
+                    2,
+                    [(Denom::Mel, CoinValue(4)), (Denom::Sym, CoinValue(5))].into()
+                ),
+                (3, mel(7)),
+            ]
+            .into()
+        );
+
+        let graph =
+            trace_descendants(&conn, &[coin(1, 0)], TraceLimit::default().depth(1)).unwrap();
+        assert_eq!(
+            summary(&graph),
+            BTreeMap::from([
+                (
+                    coin(1, 0),
+                    (0, CoinOrigin::Unexplored, CoinFate::Transaction)
+                ),
+                (
+                    coin(2, 0),
+                    (1, CoinOrigin::Transaction, CoinFate::Unexplored)
+                ),
+                (
+                    coin(2, 1),
+                    (1, CoinOrigin::Transaction, CoinFate::Unexplored)
+                ),
+            ])
+        );
+        assert_eq!(graph.txs.keys().copied().collect::<Vec<_>>(), [txhash(2)]);
+
+        let graph = trace_descendants(
+            &conn,
+            &[coin(1, 0)],
+            TraceLimit::default().height(BlockHeight(3)),
+        )
+        .unwrap();
+        assert_eq!(graph.coins[&coin(2, 1)].fate, CoinFate::Unexplored);
+        assert_eq!(graph.coins[&coin(3, 0)].fate, CoinFate::Unspent);
+        assert_eq!(
+            graph.txs.keys().copied().collect::<Vec<_>>(),
+            [txhash(2), txhash(3)]
+        );
+
+        // starting from one coin and one of its descendants follows each transaction once, at the shortest depth
+        let received = received_coins(&conn, address(1)).unwrap();
+        assert_eq!(received, [coin(1, 0), coin(2, 0)]);
+        let graph = trace_descendants(&conn, &received, TraceLimit::default()).unwrap();
+        assert_eq!(graph.coins[&coin(2, 0)].depth, 0);
+        assert_eq!(graph.coins[&coin(2, 0)].origin, CoinOrigin::Transaction);
+        assert_eq!(graph.coins[&coin(3, 0)].depth, 1);
+        assert_eq!(graph.txs.len(), 4);
+    }
+}
@@ -0,0 +1,126 @@
+use std::ops::RangeBounds;
+
+use melstructs::{BlockHeight, CoinValue, TxHash, TxKind};
+
+use crate::{
+    coinquery::parse_txhash,
+    error::retry_transient,
+    filter::{query_rows, Filters},
+    pool::Pool,
+    Error,
+};
+
+/// Info about a transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxInfo {
+    pub txhash: TxHash,
+    pub kind: TxKind,
+    pub fee: CoinValue,
+    pub covenants: Vec<Vec<u8>>,
+    /// The transaction's data. Unless full data is kept in blobs, only as much as [crate::IndexerConfig::tx_data_limit] allows is stored, by default the first kilobyte.
+    pub data: Vec<u8>,
+    /// Whether `data` is only a prefix of the transaction's data. For transactions indexed before lengths were recorded, a full kilobyte counts as truncated, since there's no telling.
+    pub data_truncated: bool,
+    pub sigs: Vec<Vec<u8>>,
+    /// Height of the block that included the transaction. Can be missing for transactions indexed by old versions.
+    pub height: Option<BlockHeight>,
+    /// Position of the transaction within its block, when sorted by txhash.
+    pub position: Option<usize>,
+}
+
+/// A half-built query on the transactions table
+#[derive(Clone)]
+pub struct TxQuery {
+    pool: Pool,
+
+    filters: Filters,
+}
+
+impl TxQuery {
+    pub(crate) fn new(pool: Pool) -> Self {
+        Self {
+            pool,
+            filters: Filters::default(),
+        }
+    }
+
+    /// Adds a constraint on the txhash.
+    pub fn txhash(mut self, txhash: TxHash) -> Self {
+        self.filters.add_eq("txhash", txhash.to_string());
+        self
+    }
+
+    /// Adds a constraint on the kind.
+    pub fn kind(mut self, kind: TxKind) -> Self {
+        self.filters.add_eq("kind", u8::from(kind));
+        self
+    }
+
+    /// Adds a constraint on the fee.
+    pub fn fee_range(mut self, range: impl RangeBounds<CoinValue>) -> Self {
+        self.filters
+            .add_range("fee", range, |f| f.0.to_be_bytes().to_vec());
+        self
+    }
+
+    /// Adds a constraint on the height of the block containing the transaction.
+    pub fn height_range(mut self, range: impl RangeBounds<u64>) -> Self {
+        self.filters.add_range("height", range, |f| *f as i64);
+        self
+    }
+
+    /// Iterate through all the transactions matching this filter. Panics if the database can't be read.
+    pub fn iter(&self) -> impl Iterator<Item = TxInfo> + '_ {
+        retry_transient(|| self.try_iter())
+            .unwrap()
+            .map(|tx| tx.unwrap())
+    }
+
+    /// Iterate through all the transactions matching this filter, returning errors instead of panicking.
+    pub fn try_iter(&self) -> Result<impl Iterator<Item = Result<TxInfo, Error>> + '_, Error> {
+        query_rows(
+            &self.pool,
+            format!(
+                "select txhash, kind, fee, covenants, coalesce(content, data), sigs, height, position, data_len from txvars left join txblobs on hash = data_hash where {}",
+                self.filters.sql()
+            ),
+            self.filters.owned_params(),
+            tx_from_row,
+        )
+    }
+}
+
+fn tx_from_row(row: &rusqlite::Row) -> Result<TxInfo, Error> {
+    let txhash: String = row.get(0)?;
+    let txhash = parse_txhash(&txhash)?;
+    let kind: u8 = row.get(1)?;
+    let kind = TxKind::try_from(kind).map_err(|_| Error::corrupt("transaction kind", kind))?;
+    let fee: CoinValue = u128::from_be_bytes(row.get(2)?).into();
+    let covenants: String = row.get(3)?;
+    let data: Vec<u8> = row.get(4)?;
+    let sigs: String = row.get(5)?;
+    let height: Option<u64> = row.get(6)?;
+    let position: Option<usize> = row.get(7)?;
+    let data_len: Option<usize> = row.get(8)?;
+    let data_truncated = data_len.map_or(data.len() >= 1024, |len| data.len() < len);
+    Ok(TxInfo {
+        txhash,
+        kind,
+        fee,
+        covenants: decode_hex_list(&covenants)?,
+        data,
+        data_truncated,
+        sigs: decode_hex_list(&sigs)?,
+        height: height.map(BlockHeight),
+        position,
+    })
+}
+
+/// Decodes a JSON list of hex strings, the way covenants and signatures are stored.
+fn decode_hex_list(json: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let list: Vec<String> =
+        serde_json::from_str(json).map_err(|_| Error::corrupt("hex list", json))?;
+    list.into_iter()
+        .map(|s| hex::decode(&s).map_err(|_| Error::corrupt("hex string", s)))
+        .collect()
+}
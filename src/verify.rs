@@ -0,0 +1,41 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use melstructs::{Block, Header};
+use novasmt::{Database, InMemoryCas};
+use tmelcrypt::HashVal;
+
+/// Checks that a block follows the given previous header hash.
+pub(crate) fn verify_linkage(blk: &Block, previous: HashVal) -> anyhow::Result<()> {
+    if blk.header.previous != previous {
+        anyhow::bail!(
+            "block {} does not follow the previous height: it points to {}, but we have {}",
+            blk.header.height,
+            blk.header.previous,
+            previous
+        )
+    }
+    Ok(())
+}
+
+/// Checks that the raw staker mapping returned by a node hashes to the stakes root in the header.
+pub(crate) fn verify_stakes(
+    header: &Header,
+    stakes: &BTreeMap<HashVal, Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut tree = Database::new(InMemoryCas::default())
+        .get_tree([0u8; 32])
+        .context("cannot create an empty tree")?;
+    for (key, doc) in stakes {
+        tree.insert(key.0, doc);
+    }
+    if HashVal(tree.root_hash()) != header.stakes_hash {
+        anyhow::bail!(
+            "stakers of block {} hash to {}, but the header says {}",
+            header.height,
+            HashVal(tree.root_hash()),
+            header.stakes_hash
+        )
+    }
+    Ok(())
+}